@@ -0,0 +1,217 @@
+//! The admin API, for inspecting and managing the cached repositories.
+//!
+//! Requests must carry the token read from `--admin-token-file` as a bearer token. Repositories
+//! are named as they appear in clone URLs, e.g. `github.com/user/repo`.
+//!
+//! - `GET /_admin/repos`: list the cached repositories
+//! - `GET /_admin/repos/<repo>`: show the details of a repository
+//! - `DELETE /_admin/repos/<repo>`: purge a repository, from disk and from the index
+//! - `POST /_admin/refresh/<repo>`: fetch a repository from its upstream now
+//! - `PUT /_admin/pins/<repo>` and `DELETE /_admin/pins/<repo>`: pin or unpin a repository
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use axum::extract::{self, Request, State};
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::Response;
+use axum::routing::{get, post, put};
+use axum::{Json, Router};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, Result};
+use crate::repo::{self, Index};
+
+/// The token clients must present.
+///
+/// Only its digest is kept and compared, so that the comparison can't leak the token through its
+/// timing.
+#[derive(Clone)]
+pub struct Token([u8; 32]);
+
+impl Token {
+    /// Read the token from the file at `path`, ignoring surrounding whitespace.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        let token = contents.trim();
+        if token.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{path:?}: empty admin token"),
+            ));
+        }
+        Ok(Self(Sha256::digest(token).into()))
+    }
+
+    fn matches(&self, auth: Option<&HeaderValue>) -> bool {
+        let Some(token) = auth.and_then(|auth| auth.as_bytes().strip_prefix(b"Bearer ")) else {
+            return false;
+        };
+        <[u8; 32]>::from(Sha256::digest(token)) == self.0
+    }
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Token(..)")
+    }
+}
+
+/// Marks the requests received on `--admin-listen` addresses.
+#[derive(Clone, Copy, Debug)]
+pub struct Listener;
+
+#[derive(Debug)]
+struct Admin {
+    repos: Arc<Index>,
+    token: Token,
+    /// Whether the admin API is only served to requests marked with `Listener`.
+    separate_listener: bool,
+}
+
+/// The routes of the admin API, all under `/_admin/`.
+pub fn router(repos: Arc<Index>, token: Token, separate_listener: bool) -> Router {
+    let admin = Arc::new(Admin {
+        repos,
+        token,
+        separate_listener,
+    });
+
+    Router::new()
+        .route("/_admin/repos", get(handle_list))
+        .route(
+            "/_admin/repos/*repo",
+            get(handle_details).delete(handle_purge),
+        )
+        .route("/_admin/refresh/*repo", post(handle_refresh))
+        .route("/_admin/pins/*repo", put(handle_pin).delete(handle_unpin))
+        .route_layer(middleware::from_fn_with_state(admin.clone(), authenticate))
+        .with_state(admin)
+}
+
+async fn authenticate(
+    State(admin): State<Arc<Admin>>,
+    request: Request,
+    next: Next,
+) -> Result<Response> {
+    // Don't even reveal the admin API on the other listeners.
+    if admin.separate_listener && request.extensions().get::<Listener>().is_none() {
+        return Err(Error::NotFound);
+    }
+
+    if !admin
+        .token
+        .matches(request.headers().get(header::AUTHORIZATION))
+    {
+        return Err(Error::MissingAuth(HeaderValue::from_static(
+            "Bearer realm=\"git-cache-http-server admin\"",
+        )));
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// A cached repository, as listed.
+#[derive(Debug, Serialize)]
+struct Status {
+    name: String,
+    upstream: String,
+    /// Disk usage in bytes, as of the last fetch or maintenance.
+    size: u64,
+    last_fetch: Option<String>,
+    last_access: String,
+    pinned: bool,
+}
+
+impl From<repo::Status> for Status {
+    fn from(status: repo::Status) -> Self {
+        Self {
+            name: status.name.display().to_string(),
+            upstream: status.upstream.to_string(),
+            size: status.size,
+            last_fetch: status.last_fetch.map(timestamp),
+            last_access: timestamp(status.last_access),
+            pinned: status.pinned,
+        }
+    }
+}
+
+/// A cached repository, in detail.
+#[derive(Debug, Serialize)]
+struct Details {
+    #[serde(flatten)]
+    status: Status,
+    /// While fetching, the other details aren't available.
+    fetching: bool,
+    #[serde(flatten)]
+    details: Option<MoreDetails>,
+}
+
+#[derive(Debug, Serialize)]
+struct MoreDetails {
+    local: PathBuf,
+    head: Option<String>,
+    pool: Option<String>,
+    fresh: bool,
+}
+
+fn timestamp(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+async fn handle_list(State(admin): State<Arc<Admin>>) -> Json<Vec<Status>> {
+    let list = admin.repos.list().await;
+    Json(list.into_iter().map(Status::from).collect())
+}
+
+async fn handle_details(
+    State(admin): State<Arc<Admin>>,
+    extract::Path(repo): extract::Path<PathBuf>,
+) -> Result<Json<Details>> {
+    let (status, details) = admin.repos.details(&repo).await?;
+    Ok(Json(Details {
+        status: status.into(),
+        fetching: details.is_none(),
+        details: details.map(|details| MoreDetails {
+            local: details.local,
+            head: details.head,
+            pool: details.pool,
+            fresh: details.fresh,
+        }),
+    }))
+}
+
+async fn handle_purge(
+    State(admin): State<Arc<Admin>>,
+    extract::Path(repo): extract::Path<PathBuf>,
+) -> Result<StatusCode> {
+    admin.repos.purge(&repo).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn handle_refresh(
+    State(admin): State<Arc<Admin>>,
+    extract::Path(repo): extract::Path<PathBuf>,
+) -> Result<StatusCode> {
+    admin.repos.refresh(&repo).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn handle_pin(
+    State(admin): State<Arc<Admin>>,
+    extract::Path(repo): extract::Path<PathBuf>,
+) -> Result<Json<Status>> {
+    Ok(Json(admin.repos.pin(&repo, true).await?.into()))
+}
+
+async fn handle_unpin(
+    State(admin): State<Arc<Admin>>,
+    extract::Path(repo): extract::Path<PathBuf>,
+) -> Result<Json<Status>> {
+    Ok(Json(admin.repos.pin(&repo, false).await?.into()))
+}
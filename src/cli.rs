@@ -0,0 +1,381 @@
+//! Maintenance commands, which work on the cache directory directly instead of through a server.
+//!
+//! They use the same `Index` and `Git` as the server, and so the same layout, metadata and
+//! options. Commands that modify the cache need it to be locked exclusively, and so can't run
+//! alongside a server; the others only need a shared lock.
+
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, bail, Context};
+use axum::http::Uri;
+use clap::Subcommand;
+
+use crate::listen::Address;
+use crate::lock::{self, CacheLock};
+use crate::pool;
+use crate::repo::{self, Index};
+use crate::server::Options;
+
+#[cfg(not(test))]
+use crate::git::Git;
+#[cfg(test)]
+use crate::git::MockGit as Git;
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum Command {
+    /// List the cached repositories.
+    Ls,
+
+    /// Clone or update repositories from their upstreams, with the server's own credentials.
+    Warm {
+        /// Upstream URL, e.g. `https://github.com/user/repo`; only HTTPS is supported, and the
+        /// scheme may be omitted.
+        #[arg(required = true, value_name = "URL")]
+        urls: Vec<String>,
+    },
+
+    /// Delete repositories from the cache.
+    Purge {
+        /// Repository as it appears in clone URLs, e.g. `github.com/user/repo`.
+        #[arg(required = true, value_name = "REPO")]
+        repos: Vec<PathBuf>,
+    },
+
+    /// Run maintenance on all repositories, clean up after interrupted operations, and evict
+    /// repositories over `--disk-quota`.
+    Gc,
+
+    /// Check the integrity of all repositories with `git fsck`.
+    Verify,
+
+    /// Report the disk usage of each repository, and of the cache as a whole.
+    Du,
+
+    /// Check that a running server is ready, exiting with a non-zero status if not.
+    Healthcheck {
+        /// The server's readiness endpoint; defaults to `/readyz` on the first TCP address it
+        /// listens on.
+        #[arg(long)]
+        url: Option<String>,
+
+        /// Give up after DURATION.
+        #[arg(long, default_value = "5s", value_name = "DURATION", value_parser = humantime::parse_duration)]
+        timeout: Duration,
+    },
+}
+
+/// Run `command` with `options`, which are otherwise the server's.
+pub async fn run(command: &Command, options: &Options) -> anyhow::Result<()> {
+    let mode = match command {
+        Command::Healthcheck { url, timeout } => {
+            let url = url.clone().unwrap_or_else(|| readiness_url(options));
+            return healthcheck(&url, *timeout).await;
+        }
+        Command::Ls | Command::Verify | Command::Du => lock::Mode::Shared,
+        Command::Warm { .. } | Command::Purge { .. } | Command::Gc => lock::Mode::Exclusive,
+    };
+
+    let _lock = CacheLock::acquire(&options.cache_dir, mode)
+        .with_context(|| format!("failed to lock cache directory {:?}", options.cache_dir))?;
+    let index = Index::new(
+        options.cache_dir.clone(),
+        Git::default(),
+        options.repo_config(),
+    );
+    index.load().await?;
+
+    match command {
+        Command::Ls => ls(&index).await,
+        Command::Warm { urls } => warm(&index, urls).await,
+        Command::Purge { repos } => purge(&index, repos).await,
+        Command::Gc => gc(&index, options).await,
+        Command::Verify => verify(&index, options).await,
+        Command::Du => du(&index, options).await,
+        Command::Healthcheck { .. } => unreachable!("healthchecks don't use the cache directory"),
+    }
+}
+
+async fn ls(index: &Index) -> anyhow::Result<()> {
+    println!("NAME\tSIZE\tLAST FETCH\tLAST ACCESS\tPINNED\tUPSTREAM");
+    for status in index.list().await {
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            status.name.display(),
+            format_size(status.size),
+            status.last_fetch.map_or(String::from("never"), timestamp),
+            timestamp(status.last_access),
+            if status.pinned { "yes" } else { "no" },
+            status.upstream,
+        );
+    }
+    Ok(())
+}
+
+async fn warm(index: &Index, urls: &[String]) -> anyhow::Result<()> {
+    let mut failed = 0;
+    for url in urls {
+        let result = match upstream(url) {
+            Ok(upstream) => index.warm(upstream).await.map_err(anyhow::Error::from),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) => println!("warmed {url}"),
+            Err(err) => {
+                eprintln!("failed to warm {url}: {err:#}");
+                failed += 1;
+            }
+        }
+    }
+
+    // Like the server does after fetching.
+    index.evict().await?;
+
+    if failed > 0 {
+        bail!("failed to warm {failed} of {} repositories", urls.len());
+    }
+    Ok(())
+}
+
+async fn purge(index: &Index, repos: &[PathBuf]) -> anyhow::Result<()> {
+    for repo in repos {
+        index
+            .purge(repo)
+            .await
+            .with_context(|| format!("failed to purge {repo:?}"))?;
+        println!("purged {}", repo.display());
+    }
+    Ok(())
+}
+
+async fn gc(index: &Index, options: &Options) -> anyhow::Result<()> {
+    // With the cache locked exclusively, no git processes can be running on it.
+    let removed = repo::remove_stale_locks(options.cache_dir.clone()).await?;
+    if removed > 0 {
+        println!("removed {removed} stale lock files");
+    }
+    index.empty_trash().await?;
+    index.maintain_all().await;
+    index.evict().await?;
+    Ok(())
+}
+
+async fn verify(index: &Index, options: &Options) -> anyhow::Result<()> {
+    let list = index.list().await;
+    let mut failed = 0;
+    for status in &list {
+        let local = options.cache_dir.join(&status.name);
+        match index.git().verify(local).await {
+            Ok(()) => println!("ok\t{}", status.name.display()),
+            Err(err) => {
+                println!("FAILED\t{}", status.name.display());
+                eprintln!("{}: {err:#}", status.name.display());
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        bail!(
+            "{failed} of {} repositories failed verification",
+            list.len()
+        );
+    }
+    Ok(())
+}
+
+async fn du(index: &Index, options: &Options) -> anyhow::Result<()> {
+    for status in index.list().await {
+        let size = repo::disk_usage(options.cache_dir.join(&status.name)).await?;
+        println!("{}\t{}", format_size(size), status.name.display());
+    }
+    for shared in [pool::POOLS_DIR, ".lfs"] {
+        let path = options.cache_dir.join(shared);
+        if path.exists() {
+            println!("{}\t{shared}", format_size(repo::disk_usage(path).await?));
+        }
+    }
+    let total = repo::disk_usage(options.cache_dir.clone()).await?;
+    println!("{}\ttotal", format_size(total));
+    Ok(())
+}
+
+async fn healthcheck(url: &str, timeout: Duration) -> anyhow::Result<()> {
+    let client = reqwest::Client::builder().timeout(timeout).build()?;
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .with_context(|| format!("failed to reach {url}"))?;
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    if !status.is_success() {
+        bail!("not ready ({status}): {}", body.trim());
+    }
+    println!("{}", body.trim());
+    Ok(())
+}
+
+/// Where a server started with `options` reports its readiness.
+fn readiness_url(options: &Options) -> String {
+    let scheme = match options.tls_cert {
+        Some(_) => "https",
+        None => "http",
+    };
+    let address = options.listen.iter().find_map(|address| match address {
+        Address::Tcp(address) => Some(address.clone()),
+        Address::Unix(_) => None,
+    });
+    let address = address.unwrap_or_else(|| format!("0.0.0.0:{}", options.port));
+
+    // The unspecified addresses can be reached through loopback.
+    let address = if let Some(port) = address.strip_prefix("0.0.0.0:") {
+        format!("127.0.0.1:{port}")
+    } else if let Some(port) = address.strip_prefix("[::]:") {
+        format!("[::1]:{port}")
+    } else {
+        address
+    };
+
+    format!("{scheme}://{address}/readyz")
+}
+
+/// The upstream for `url`, defaulting to HTTPS like the server does.
+fn upstream(url: &str) -> anyhow::Result<Uri> {
+    let url = match url.contains("://") {
+        true => url.to_string(),
+        false => format!("https://{url}"),
+    };
+    let upstream: Uri = url
+        .parse()
+        .with_context(|| format!("invalid URL {url:?}"))?;
+    // Upstreams are always reached over HTTPS, like for clients of the server, since the scheme
+    // isn't part of where repositories are cached.
+    if upstream.scheme_str() != Some("https") {
+        return Err(anyhow!(
+            "unsupported scheme in {url:?}, only https is supported"
+        ));
+    }
+    Ok(upstream)
+}
+
+fn timestamp(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+/// Format `size` in bytes with the same suffixes accepted by the options.
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 3] = ["K", "M", "G"];
+
+    if size < 1 << 10 {
+        return size.to_string();
+    }
+    let (mut size, mut unit) = (size as f64 / 1024.0, 0);
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1}{}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn options(args: &[&str]) -> Options {
+        Options::parse_from(["git-cache-http-server"].iter().chain(args))
+    }
+
+    #[test]
+    fn subcommands() {
+        let options = options(&["--cache-dir", "/tmp/cache", "warm", "example.com/a"]);
+        assert_eq!(options.cache_dir, PathBuf::from("/tmp/cache"));
+        assert!(
+            matches!(options.command, Some(Command::Warm { urls }) if urls == ["example.com/a"])
+        );
+
+        assert!(Options::try_parse_from(["git-cache-http-server", "warm"]).is_err());
+        assert!(Options::try_parse_from(["git-cache-http-server"])
+            .unwrap()
+            .command
+            .is_none());
+    }
+
+    #[test]
+    fn readiness_urls() {
+        assert_eq!(readiness_url(&options(&[])), "http://127.0.0.1:8080/readyz");
+        assert_eq!(
+            readiness_url(&options(&[
+                "--listen",
+                "unix:/tmp/a",
+                "--listen",
+                "[::]:80"
+            ])),
+            "http://[::1]:80/readyz"
+        );
+        assert_eq!(
+            readiness_url(&options(&[
+                "--listen",
+                "cache.example.com:443",
+                "--tls-cert",
+                "cert.pem",
+                "--tls-key",
+                "key.pem"
+            ])),
+            "https://cache.example.com:443/readyz"
+        );
+    }
+
+    #[test]
+    fn upstreams() {
+        assert_eq!(upstream("example.com/a").unwrap(), "https://example.com/a");
+        assert_eq!(
+            upstream("https://example.com/a").unwrap(),
+            "https://example.com/a"
+        );
+        assert!(upstream("http://example.com/a").is_err());
+        assert!(upstream("ssh://example.com/a").is_err());
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(format_size(1023), "1023");
+        assert_eq!(format_size(1536), "1.5K");
+        assert_eq!(format_size(5 << 30), "5.0G");
+        assert_eq!(format_size(2048 << 30), "2048.0G");
+    }
+
+    #[tokio::test]
+    async fn warm_and_purge() {
+        let cache_dir = tempdir().unwrap().into_path();
+
+        let mut mock_git = Git::default();
+        mock_git.expect_init().times(2).returning(|_| Ok(()));
+        mock_git
+            .expect_fetch()
+            .times(2)
+            .returning(|upstream, _, _| match upstream.host() {
+                Some("example.com") => Ok(()),
+                _ => Err(crate::error::Error::NotFound),
+            });
+
+        let index = Index::new(cache_dir.clone(), mock_git, Default::default());
+        let urls = [String::from("example.com/a"), "example.org/b".into()];
+        let err = warm(&index, &urls).await.unwrap_err();
+        assert_eq!(err.to_string(), "failed to warm 1 of 2 repositories");
+        // The repository that failed isn't left behind.
+        let [warmed] = &index.list().await[..] else {
+            panic!("expected a single repository");
+        };
+        assert!(warmed.last_fetch.is_some());
+        assert!(!cache_dir.join("example.org").join("b.git").exists());
+
+        purge(&index, &[PathBuf::from("example.com/a")])
+            .await
+            .unwrap();
+        assert!(!cache_dir.join("example.com/a.git").exists());
+    }
+}
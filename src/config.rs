@@ -0,0 +1,261 @@
+//! The configuration file, layered under the command line options.
+//!
+//! Keys are the same as the long command line options. Options that are specific to an upstream
+//! host go in `[hosts."<host>"]` sections.
+//!
+//! ```toml
+//! cache-dir = "/var/cache/git"
+//! fresh-for = "1m"
+//! disk-quota = "500G"
+//!
+//! [fork-groups]
+//! linux = ["github.com/torvalds/linux", "github.com/gregkh/linux"]
+//!
+//! [hosts."github.com"]
+//! fresh-for = "5m"
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::de::{self, Deserializer, Visitor};
+use serde::Deserialize;
+
+use crate::listen::{self, Address, Owner};
+use crate::mirror::Mirror;
+use crate::pool;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct File {
+    pub cache_dir: Option<PathBuf>,
+    pub port: Option<u16>,
+    #[serde(deserialize_with = "parsed")]
+    pub listen: Vec<Address>,
+    #[serde(deserialize_with = "mode")]
+    pub socket_mode: Option<u32>,
+    #[serde(deserialize_with = "owner")]
+    pub socket_owner: Option<Owner>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub admin_token_file: Option<PathBuf>,
+    #[serde(deserialize_with = "parsed")]
+    pub admin_listen: Vec<Address>,
+    #[serde(deserialize_with = "duration")]
+    pub grace_period: Option<Duration>,
+    #[serde(deserialize_with = "duration")]
+    pub fresh_for: Option<Duration>,
+    #[serde(deserialize_with = "duration")]
+    pub serve_stale_within: Option<Duration>,
+    #[serde(deserialize_with = "size")]
+    pub max_body_size: Option<u64>,
+    #[serde(deserialize_with = "size")]
+    pub disk_quota: Option<u64>,
+    pub pin: Vec<PathBuf>,
+    #[serde(deserialize_with = "duration")]
+    pub maintenance_interval: Option<Duration>,
+    pub maintenance_after: Option<u32>,
+    pub fork_groups: BTreeMap<GroupName, Vec<PathBuf>>,
+    #[serde(deserialize_with = "mirrors")]
+    pub mirror: Vec<Mirror>,
+    pub detect_forks: Option<bool>,
+    pub hosts: BTreeMap<String, Host>,
+}
+
+/// Options for a specific upstream host.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Host {
+    #[serde(deserialize_with = "duration")]
+    pub fresh_for: Option<Duration>,
+}
+
+impl File {
+    /// Read and validate the configuration file at `path`.
+    ///
+    /// Errors include the line and column of the offending key or value.
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read configuration file {path:?}"))?;
+        Self::parse(&contents).with_context(|| format!("invalid configuration file {path:?}"))
+    }
+
+    fn parse(contents: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(contents)?)
+    }
+}
+
+/// A validated fork group name.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GroupName(pub String);
+
+impl<'de> Deserialize<'de> for GroupName {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        if !pool::is_valid_name(&name) {
+            return Err(de::Error::custom(format!("invalid group name: {name}")));
+        }
+        Ok(Self(name))
+    }
+}
+
+fn parsed<'de, D: Deserializer<'de>, T: FromStr<Err = String>>(
+    deserializer: D,
+) -> Result<Vec<T>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| s.parse().map_err(de::Error::custom))
+        .collect()
+}
+
+/// Mirrors as a table of `"<repo>" = "<duration>"`.
+fn mirrors<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Mirror>, D::Error> {
+    BTreeMap::<String, String>::deserialize(deserializer)?
+        .iter()
+        .map(|(repos, interval)| {
+            let interval = humantime::parse_duration(interval).map_err(de::Error::custom)?;
+            Mirror::new(repos, interval).map_err(de::Error::custom)
+        })
+        .collect()
+}
+
+/// An octal file mode, as a string (TOML octal integers would work too, but are easy to get wrong).
+fn mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    let mode = String::deserialize(deserializer)?;
+    listen::parse_mode(&mode)
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+fn owner<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Owner>, D::Error> {
+    let owner = String::deserialize(deserializer)?;
+    listen::parse_owner(&owner)
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    let duration = String::deserialize(deserializer)?;
+    humantime::parse_duration(&duration)
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+/// A size in bytes, either as an integer or as a string with a K, M or G suffix (in either case).
+fn size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    struct SizeVisitor;
+
+    impl<'de> Visitor<'de> for SizeVisitor {
+        type Value = u64;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a number of bytes, optionally with a K, M or G suffix (in either case)")
+        }
+
+        fn visit_i64<E: de::Error>(self, v: i64) -> Result<u64, E> {
+            u64::try_from(v).map_err(|_| E::custom("expected a number of bytes"))
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<u64, E> {
+            parse_size(v).map_err(E::custom)
+        }
+    }
+
+    deserializer.deserialize_any(SizeVisitor).map(Some)
+}
+
+/// Parse a size in bytes, optionally with a K, M or G suffix (in either case).
+pub fn parse_size(s: &str) -> std::result::Result<u64, String> {
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let unit = match unit {
+        "" => 1,
+        "K" | "k" => 1 << 10,
+        "M" | "m" => 1 << 20,
+        "G" | "g" => 1 << 30,
+        _ => return Err(format!("unknown size suffix: {unit}")),
+    };
+    let size: u64 = digits.parse().map_err(|_| "expected a number of bytes")?;
+    size.checked_mul(unit)
+        .ok_or_else(|| String::from("size too large"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing() {
+        let file = File::parse(
+            r#"
+            cache-dir = "/tmp/cache"
+            listen = ["[::1]:8080", "unix:/tmp/git-cache.sock"]
+            socket-mode = "660"
+            fresh-for = "1m"
+            max-body-size = "1M"
+            disk-quota = 1024
+            pin = ["example.com/a"]
+
+            [fork-groups]
+            mock = ["example.com/b", "example.com/c"]
+
+            [mirror]
+            "https://example.com/d" = "5m"
+            "example.com/e/*" = "1h"
+
+            [hosts."example.com"]
+            fresh-for = "5m"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(file.cache_dir, Some("/tmp/cache".into()));
+        assert_eq!(file.listen.len(), 2);
+        assert_eq!(file.socket_mode, Some(0o660));
+        assert_eq!(file.fresh_for, Some(Duration::from_secs(60)));
+        assert_eq!(file.max_body_size, Some(1 << 20));
+        assert_eq!(file.disk_quota, Some(1024));
+        assert_eq!(file.pin, [PathBuf::from("example.com/a")]);
+        assert_eq!(file.fork_groups[&GroupName("mock".into())].len(), 2);
+        assert_eq!(file.mirror.len(), 2);
+        assert_eq!(
+            file.hosts["example.com"].fresh_for,
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(file.port, None);
+    }
+
+    #[test]
+    fn errors_have_line_numbers() {
+        let err = |contents| format!("{:#}", File::parse(contents).unwrap_err());
+
+        assert!(err("port = 8080\nfresh-for = \"1 parsec\"\n").contains("line 2"));
+        assert!(
+            err("port = 8080\n\n[hosts.\"example.com\"]\nfresh-fro = \"1m\"\n").contains("line 4")
+        );
+        assert!(err("[fork-groups]\n\".hidden\" = []\n").contains("line 2"));
+        assert!(err("disk-quota = \"1P\"\n").contains("line 1"));
+        assert!(err("port = 8080\nlisten = [\"::1:8080\"]\n").contains("line 2"));
+    }
+
+    #[test]
+    fn size_parsing() {
+        assert_eq!(parse_size("42"), Ok(42));
+        assert_eq!(parse_size("2k"), Ok(2048));
+        assert_eq!(parse_size("2K"), Ok(2048));
+        assert_eq!(parse_size("64M"), Ok(64 << 20));
+        assert_eq!(parse_size("64m"), Ok(64 << 20));
+        assert_eq!(parse_size("1G"), Ok(1 << 30));
+        assert_eq!(parse_size("1g"), Ok(1 << 30));
+        assert!(parse_size("1T").is_err());
+        assert!(parse_size("1t").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("-1").is_err());
+    }
+}
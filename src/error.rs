@@ -10,11 +10,13 @@ pub type Result<T> = std::result::Result<T, Error>;
 ///
 /// These errors are for our benefit only, the client will just get a StatusCode.
 ///
-/// There are only a few types of error conditions we need to care about. The first three are
-/// modelled using this `Error` type:
+/// There are only a few types of error conditions we need to care about. These are modelled using
+/// this `Error` type:
 ///
-/// - the few specific cases where we want to reply with NOT_FOUND or BAD_REQUEST;
+/// - the few specific cases where we want to reply with NOT_FOUND, BAD_REQUEST,
+///   PAYLOAD_TOO_LARGE or CONFLICT;
 /// - (future) handling an UNAUTHORIZED response from `Git::remote_head`;
+/// - the upstream being unreachable or failing, which can sometimes be worked around;
 /// - internal server errors that cannot be recovered within that request (but that are presumed to
 ///   *not* affect all other/future requests) and can be type erased.
 ///
@@ -26,8 +28,14 @@ pub enum Error {
     NotFound,
     #[error("client error: {0}")]
     BadRequest(&'static str),
+    #[error("request body too large")]
+    PayloadTooLarge,
+    #[error("conflict: {0}")]
+    Conflict(&'static str),
     #[error("not authenticated/authorized")]
     MissingAuth(HeaderValue),
+    #[error("upstream unavailable: {0}")]
+    UpstreamUnavailable(anyhow::Error),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
@@ -40,9 +48,24 @@ impl IntoResponse for Error {
                 tracing::error!(client_error = message);
                 (StatusCode::BAD_REQUEST, message).into_response()
             }
+            Error::PayloadTooLarge => {
+                tracing::error!(client_error = "request body too large");
+                StatusCode::PAYLOAD_TOO_LARGE.into_response()
+            }
+            Error::Conflict(message) => {
+                tracing::error!(client_error = message);
+                (StatusCode::CONFLICT, message).into_response()
+            }
             Error::MissingAuth(authenticate) => {
                 (StatusCode::UNAUTHORIZED, [(WWW_AUTHENTICATE, authenticate)]).into_response()
             }
+            Error::UpstreamUnavailable(err) => {
+                tracing::error!(
+                    upstream_error = format_args!("{:#?}", err),
+                    "upstream unavailable"
+                );
+                (StatusCode::BAD_GATEWAY, "upstream unavailable").into_response()
+            }
             Error::Other(err) => {
                 // TODO: log the backtrace as well
                 tracing::error!(
@@ -1,17 +1,31 @@
+use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
 use std::os::unix::process::ExitStatusExt;
 use std::path::PathBuf;
+use std::pin::Pin;
 use std::process::{Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::task::{self, Poll};
+use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, bail, Context};
-use axum::body::Bytes;
+use anyhow::{anyhow, bail, ensure, Context};
+use axum::body::{Body, Bytes};
 use axum::http::header;
 use axum::http::{HeaderMap, HeaderValue, Uri};
-use reqwest::{Client, StatusCode};
-use tokio::io::{AsyncRead, AsyncWriteExt};
-use tokio::process::Command;
+use futures_util::TryStreamExt;
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use sync_wrapper::SyncStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
+use tokio::process::{Child, ChildStdout, Command};
+use tokio::sync::oneshot;
+use tokio_util::io::StreamReader;
 use tracing::{instrument, Instrument};
 
 use crate::error::{Error, Result};
+use crate::lfs;
+use crate::systemd;
 use crate::APP_NAME;
 
 #[cfg(test)]
@@ -21,22 +35,79 @@ use mockall::automock;
 // position) just yet. Otherwise we should be able to get by with `impl AsyncRead + Send + Unpin`.
 pub type GitAsyncRead = Box<dyn AsyncRead + Send + Unpin>;
 
+/// Something to hold on to until a child process exits, like a lock guard on its repository.
+pub type KeepAlive = Box<dyn Any + Send + Sync>;
+
+/// The `Git-Protocol` header value we use to probe upstreams for protocol v2.
+const GIT_PROTOCOL_V2: &str = "version=2";
+
+/// A protocol v2 `ls-refs` request for all refs, including the HEAD symref.
+const LS_REFS_REQUEST: &str = "0014command=ls-refs\n0001000csymrefs\n0000";
+
+/// The refs of a repository, as advertised by the upstream or read from a local copy.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Refs {
+    /// The ref HEAD points to, if known.
+    pub head: Option<String>,
+    /// All other refs, mapped to the object ids they point to.
+    pub refs: BTreeMap<String, String>,
+}
+
+impl Refs {
+    fn insert_advertised(&mut self, oid: &str, name: &str) {
+        // Skip HEAD (handled as a symref), peeled tags and the placeholder advertised for empty
+        // repositories, none of which get fetched as refs of their own.
+        if name != "HEAD" && !name.ends_with("^{}") {
+            self.refs.insert(name.to_string(), oid.to_string());
+        }
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct Git {}
 
 #[cfg_attr(test, automock, allow(dead_code))]
 impl Git {
+    /// Check that `git` and `git-upload-pack` can be run.
+    pub async fn check(&self) -> Result<()> {
+        // With `-h`, git-upload-pack prints its usage and exits with 129, which is fine.
+        const CHECKS: [(&str, &str, &[i32]); 2] = [
+            ("git", "--version", &[0]),
+            ("git-upload-pack", "-h", &[0, 129]),
+        ];
+
+        for (program, arg, ok) in CHECKS {
+            let mut command = Command::new(program);
+            for var in systemd::LISTEN_ENV {
+                command.env_remove(var);
+            }
+            let status = command
+                .arg(arg)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .await
+                .with_context(|| format!("failed to run `{program}`"))?;
+            if !status.code().is_some_and(|code| ok.contains(&code)) {
+                return Err(anyhow!("`{program} {arg}` exited with {status}").into());
+            }
+        }
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     pub async fn init(&self, local: PathBuf) -> Result<()> {
-        let output = Command::new("git")
-            .arg("init")
-            .arg("--quiet")
-            .arg("--bare")
-            .arg(local)
-            .stdin(Stdio::null())
-            .output()
-            .await
-            .expect("failed to execute `git init`");
+        let output = run(
+            Command::new("git")
+                .arg("init")
+                .arg("--quiet")
+                .arg("--bare")
+                .arg(local)
+                .stdin(Stdio::null()),
+            "git init",
+        )
+        .await?;
 
         exited_ok_with_stdout(output, "git init", "failed to initialize repository")?;
 
@@ -48,7 +119,7 @@ impl Git {
         &self,
         upstream: Uri,
         auth: Option<HeaderValue>,
-    ) -> Result<Option<String>> {
+    ) -> Result<Refs> {
         let mut extra_headers = HeaderMap::new();
 
         if let Some(auth) = auth {
@@ -56,52 +127,58 @@ impl Git {
             extra_headers.insert(header::AUTHORIZATION, auth);
         }
 
-        let response = Client::builder()
-            .user_agent(APP_NAME)
-            .build()
-            .expect("failed to build reqwest client")
+        // Probe with protocol v2. Upstreams that don't support it ignore the header and reply with
+        // a v0/v1 advertisement instead.
+        extra_headers.insert("git-protocol", HeaderValue::from_static(GIT_PROTOCOL_V2));
+
+        let client = client();
+
+        let response = client
             .get(format!("{upstream}/info/refs?service=git-upload-pack"))
-            .headers(extra_headers)
+            .headers(extra_headers.clone())
             .send()
             .await
-            .context("failed to reach upstream for /info/refs")?;
-
-        match response.status() {
-            StatusCode::OK => { /* keep going */ }
-            StatusCode::NOT_FOUND => return Err(Error::NotFound),
-            StatusCode::UNAUTHORIZED => {
-                let authenticate = response
-                    .headers()
-                    .get(header::WWW_AUTHENTICATE)
-                    .cloned()
-                    .ok_or(anyhow!(
-                    "missing WWW-Authenticate header for 401 Unauthorized response from upstream"
-                ))?;
-                return Err(Error::MissingAuth(authenticate));
-            }
-            code => {
-                return Err(anyhow!("upstream responded to /info/refs with status {code}").into())
-            }
-        };
+            .map_err(|err| unavailable(err, "failed to reach upstream for /info/refs"))?;
 
-        let content_type = response.headers().get(header::CONTENT_TYPE);
-        if !matches!(
-            content_type,
-            Some(v) if v == "application/x-git-upload-pack-advertisement"
-        ) {
-            return Err(anyhow!(
-                "upstream response content-type doesn't match smart v1 protocol: {content_type:?}"
-            )
-            .into());
-        }
+        check_upstream_response(
+            &response,
+            "/info/refs",
+            "application/x-git-upload-pack-advertisement",
+        )?;
 
         let response = response
             .bytes()
             .await
             .context("failed to read full response from upstream /info/refs")?;
 
-        Ok(parse_smart_refs(response)
-            .context("failed to parse response from upstream /info/refs")?)
+        if let Advertisement::V0(refs) = parse_smart_refs(response)
+            .context("failed to parse response from upstream /info/refs")?
+        {
+            return Ok(refs);
+        }
+
+        // A v2 advertisement only lists capabilities, so refs must be requested with `ls-refs`.
+        let response = client
+            .post(format!("{upstream}/git-upload-pack"))
+            .headers(extra_headers)
+            .header(
+                header::CONTENT_TYPE,
+                "application/x-git-upload-pack-request",
+            )
+            .header(header::ACCEPT, "application/x-git-upload-pack-result")
+            .body(LS_REFS_REQUEST)
+            .send()
+            .await
+            .map_err(|err| unavailable(err, "failed to reach upstream for ls-refs"))?;
+
+        check_upstream_response(&response, "ls-refs", "application/x-git-upload-pack-result")?;
+
+        let response = response
+            .bytes()
+            .await
+            .context("failed to read full response from upstream ls-refs")?;
+
+        Ok(parse_ls_refs(response).context("failed to parse response from upstream ls-refs")?)
     }
 
     #[instrument(skip(self))]
@@ -113,6 +190,9 @@ impl Git {
     ) -> Result<()> {
         let mut command = Command::new("git");
 
+        // Failures are classified by their messages, so keep git from translating them.
+        command.env("LC_ALL", "C").env("LANGUAGE", "");
+
         if let Some(auth) = auth {
             assert!(auth.is_sensitive());
 
@@ -127,77 +207,269 @@ impl Git {
             }
         }
 
-        let output = command
-            .arg("-C")
-            .arg(local)
-            .arg("fetch")
-            .arg("--quiet")
-            .arg("--prune-tags")
-            .arg(upstream.to_string())
-            .arg("+refs/*:refs/*") // Map all upstream refs to local refs.
-            .stdin(Stdio::null())
-            .output()
+        let output = run(
+            command
+                .arg("-C")
+                .arg(local)
+                .arg("fetch")
+                .arg("--quiet")
+                .arg("--prune-tags")
+                .arg(upstream.to_string())
+                .arg("+refs/*:refs/*") // Map all upstream refs to local refs.
+                .stdin(Stdio::null()),
+            "git fetch",
+        )
+        .await?;
+
+        let upstream_unavailable = fetch_failed_on_upstream(&output.stderr);
+
+        match exited_ok_with_stdout(output, "git fetch", "failed to fetch from upstream") {
+            Err(Error::Other(err)) if upstream_unavailable => Err(Error::UpstreamUnavailable(err)),
+            result => result.map(drop),
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn local_refs(&self, local: PathBuf) -> Result<BTreeMap<String, String>> {
+        let output = run(
+            Command::new("git")
+                .arg("-C")
+                .arg(local)
+                .arg("for-each-ref")
+                .arg("--format=%(objectname) %(refname)")
+                .stdin(Stdio::null()),
+            "git for-each-ref",
+        )
+        .await?;
+
+        let stdout =
+            exited_ok_with_stdout(output, "git for-each-ref", "failed to list local refs")?;
+
+        Ok(parse_local_refs(&stdout).context("failed to parse local refs")?)
+    }
+
+    /// Optimize a repository for serving: repack it, writing a multi-pack-index with reachability
+    /// bitmaps, update the commit-graph, and prune unreachable loose objects.
+    ///
+    /// Objects borrowed through alternates are left out of the new pack (and thus dropped), so
+    /// any object pool must be maintained first.
+    ///
+    /// Runs at low CPU and I/O priority, so that serving clients takes precedence.
+    #[instrument(skip(self))]
+    pub async fn maintain(&self, local: PathBuf) -> Result<()> {
+        const STEPS: &[(&str, &[&str])] = &[
+            (
+                "git repack",
+                &["repack", "-a", "-d", "-l", "-b", "--write-midx", "--quiet"],
+            ),
+            (
+                "git commit-graph",
+                &["commit-graph", "write", "--reachable", "--no-progress"],
+            ),
+            ("git prune", &["prune", "--expire=1.hour.ago"]),
+        ];
+
+        for (process_name, args) in STEPS {
+            let output = run(
+                low_priority(&mut Command::new("git"))
+                    .arg("-C")
+                    .arg(&local)
+                    .args(*args)
+                    .stdin(Stdio::null()),
+                process_name,
+            )
+            .await?;
+
+            exited_ok_with_stdout(output, process_name, "failed to maintain repository")?;
+        }
+
+        Ok(())
+    }
+
+    /// Check the connectivity and validity of the objects in `local`.
+    #[instrument(skip(self))]
+    pub async fn verify(&self, local: PathBuf) -> Result<()> {
+        let output = run(
+            low_priority(&mut Command::new("git"))
+                .arg("-C")
+                .arg(local)
+                .args(["fsck", "--no-progress", "--no-dangling"])
+                .stdin(Stdio::null()),
+            "git fsck",
+        )
+        .await?;
+
+        // Keep what fsck found (problems go to stdout, errors to stderr), for reporting.
+        if !output.status.success() {
+            let found = [output.stdout, output.stderr].concat();
+            let found = String::from_utf8_lossy(&found);
+            let err = anyhow!("{}", found.trim()).context("repository failed verification");
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    /// The root commit of the history of HEAD, if any.
+    ///
+    /// Histories with more than one root commit are identified by the lowest one.
+    #[instrument(skip(self))]
+    pub async fn root_commit(&self, local: PathBuf) -> Result<Option<String>> {
+        let output = run(
+            Command::new("git")
+                .arg("-C")
+                .arg(local)
+                .arg("rev-list")
+                .arg("--max-parents=0")
+                .arg("--ignore-missing") // HEAD may still be unborn.
+                .arg("HEAD")
+                .stdin(Stdio::null()),
+            "git rev-list",
+        )
+        .await?;
+
+        let stdout = exited_ok_with_stdout(output, "git rev-list", "failed to find root commit")?;
+        let stdout = String::from_utf8(stdout).context("root commit is not valid UTF-8")?;
+
+        Ok(stdout.lines().min().map(str::to_string))
+    }
+
+    /// Fetch all refs of `local` into object pool `pool`, under `refs/forks/<member>/`.
+    ///
+    /// The pool is initialized if it doesn't exist yet.
+    #[instrument(skip(self))]
+    pub async fn sync_pool(&self, pool: PathBuf, local: PathBuf, member: String) -> Result<()> {
+        if !tokio::fs::try_exists(&pool)
             .await
-            .expect("failed to execute `git fetch`");
+            .context("failed to check for object pool")?
+        {
+            self.init(pool.clone()).await?;
+        }
 
-        exited_ok_with_stdout(output, "git fetch", "failed to fetch from upstream")?;
+        let output = run(
+            Command::new("git")
+                .arg("-c")
+                .arg("gc.auto=0") // Pools are only maintained along with their members.
+                .arg("-C")
+                .arg(pool)
+                .arg("fetch")
+                .arg("--quiet")
+                .arg("--no-tags")
+                .arg("--prune")
+                .arg("--no-write-fetch-head")
+                .arg(local)
+                .arg(format!("+refs/*:refs/forks/{member}/*"))
+                .stdin(Stdio::null()),
+            "git fetch",
+        )
+        .await?;
+
+        exited_ok_with_stdout(output, "git fetch", "failed to sync object pool")?;
 
         Ok(())
     }
 
+    /// Delete the refs of `member` from object pool `pool`.
+    ///
+    /// Its objects are only dropped the next time the pool is maintained.
     #[instrument(skip(self))]
-    pub fn advertise_refs(&self, local: PathBuf) -> Result<GitAsyncRead> {
-        let mut child = Command::new("git-upload-pack")
-            .arg("--stateless-rpc")
-            .arg("--http-backend-info-refs")
-            .arg(local)
-            .stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .expect("failed to spawn `git-upload-pack`");
-
-        let stdout = child.stdout.take().expect("stdout should be piped");
-
-        // The stdout output will be handed off to axum to transmit it to the client. Therefore,
-        // spawn a separete task to wait for and reape the child process when its done, instead of
-        // relying on tokio doing that on a best-effort-only basis. This also allow us to log any
-        // errors.
-        tokio::spawn(
-            async move {
-                let output = child
-                    .wait_with_output()
-                    .await
-                    .expect("failed to wait for `git-upload-pack` to exit");
-                if !output.status.success() {
-                    tracing::error!(
-                        status = output.status.into_raw(),
-                        stderr = ?Bytes::from(output.stderr),
-                        "`git-upload-pack` exited with non-zero status",
-                    );
-                } else {
-                    tracing::trace!("`git-upload-pack` exited with 0");
-                }
-            }
-            .in_current_span(),
-        );
+    pub async fn leave_pool(&self, pool: PathBuf, member: String) -> Result<()> {
+        let output = run(
+            Command::new("git")
+                .arg("-C")
+                .arg(&pool)
+                .arg("for-each-ref")
+                .arg("--format=delete %(refname)")
+                .arg(format!("refs/forks/{member}/"))
+                .stdin(Stdio::null()),
+            "git for-each-ref",
+        )
+        .await?;
+
+        let commands =
+            exited_ok_with_stdout(output, "git for-each-ref", "failed to list pool refs")?;
+
+        let (mut child, running) = spawn(
+            Command::new("git")
+                .arg("-C")
+                .arg(&pool)
+                .arg("update-ref")
+                .arg("--stdin")
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()),
+            "git update-ref",
+        )?;
+
+        let mut stdin = child.stdin.take().expect("stdin should be piped");
+        stdin
+            .write_all(&commands)
+            .await
+            .context("failed to write to `git update-ref`")?;
+        drop(stdin);
 
-        Ok(Box::new(stdout))
+        let output = child
+            .wait_with_output()
+            .await
+            .expect("failed to wait for `git update-ref`");
+        drop(running);
+
+        exited_ok_with_stdout(output, "git update-ref", "failed to delete pool refs")?;
+
+        Ok(())
     }
 
-    #[instrument(skip(self, input))]
-    pub async fn upload_pack(&self, local: PathBuf, input: Bytes) -> Result<GitAsyncRead> {
-        let mut child = Command::new("git-upload-pack")
-            .arg("--stateless-rpc")
-            .arg(local)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .expect("failed to spawn `git-upload-pack`");
+    #[instrument(skip(self, keep_alive))]
+    pub fn advertise_refs(
+        &self,
+        local: PathBuf,
+        protocol: Option<String>,
+        keep_alive: KeepAlive,
+    ) -> Result<GitAsyncRead> {
+        let mut command = Command::new("git-upload-pack");
+
+        if let Some(protocol) = protocol {
+            command.env("GIT_PROTOCOL", protocol);
+        }
+
+        let (child, running) = spawn(
+            command
+                .arg("--stateless-rpc")
+                .arg("--http-backend-info-refs")
+                .arg(local)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()),
+            "git-upload-pack",
+        )?;
+
+        Ok(supervise(child, running, "git-upload-pack", keep_alive))
+    }
+
+    #[instrument(skip(self, input, keep_alive))]
+    pub async fn upload_pack(
+        &self,
+        local: PathBuf,
+        protocol: Option<String>,
+        mut input: GitAsyncRead,
+        keep_alive: KeepAlive,
+    ) -> Result<GitAsyncRead> {
+        let mut command = Command::new("git-upload-pack");
+
+        if let Some(protocol) = protocol {
+            command.env("GIT_PROTOCOL", protocol);
+        }
+
+        let (mut child, running) = spawn(
+            command
+                .arg("--stateless-rpc")
+                .arg(local)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()),
+            "git-upload-pack",
+        )?;
 
         let mut stdin = child.stdin.take().expect("stdin should be piped");
-        let stdout = child.stdout.take().expect("stdout should be piped");
 
         // While in general we expect git-upload-pack to process its entire input before writing
         // anything to its output, that's might not be necessarily true in all cases.
@@ -206,46 +478,179 @@ impl Git {
         // output will be forwarded by axum to the client, *after* the HTTP status code has already
         // been sent (200 OK).
         //
-        // Therefore we don't really have to return write errors to the client. And with the
-        // current git op abstraction, it wouldn't be possible to do it (changing the abstraction
-        // is hard because it has to be easily mockable in tests). So instead just log any such
-        // errors.
+        // Therefore we don't really have to return i/o errors to the client. And with the current
+        // git op abstraction, it wouldn't be possible to do it (changing the abstraction is hard
+        // because it has to be easily mockable in tests). So instead just log any such errors;
+        // closing stdin early will make git-upload-pack fail as well.
         tokio::spawn(
             async move {
-                if let Err(err) = stdin.write_all(&input).await {
-                    tracing::error!(error = ?err, "i/o error while writing to git-upload-pack");
-                } else {
-                    tracing::trace!("done writing to `git-upload-pack`");
+                match tokio::io::copy(&mut input, &mut stdin).await {
+                    Ok(written) => {
+                        tracing::trace!(written, "done writing to `git-upload-pack`");
+                    }
+                    // git-upload-pack may exit without reading all of its input, e.g. when it's
+                    // killed because the client went away or sent too much.
+                    Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {
+                        tracing::debug!(error = ?err, "git-upload-pack stopped reading its input");
+                    }
+                    Err(err) => {
+                        tracing::error!(error = ?err, "i/o error while piping input to git-upload-pack");
+                    }
                 }
             }
             .in_current_span(),
         );
 
-        // The stdout output will be handed off to axum to transmit it to the client. Therefore,
-        // spawn a separete task to wait for and reape the child process when its done, instead of
-        // relying on tokio doing that on a best-effort-only basis. This also allow us to log any
-        // errors.
-        tokio::spawn(
-            async move {
-                let output = child
-                    .wait_with_output()
-                    .await
-                    .expect("failed to wait for `git-upload-pack` to exit");
-                if !output.status.success() {
-                    tracing::error!(
-                        status = output.status.into_raw(),
-                        stderr = ?Bytes::from(output.stderr),
-                        "`git-upload-pack` exited with non-zero status",
-                    );
-                } else {
-                    tracing::trace!("`git-upload-pack` exited with 0");
-                }
-            }
-            .in_current_span(),
-        );
+        Ok(supervise(child, running, "git-upload-pack", keep_alive))
+    }
 
-        Ok(Box::new(stdout))
+    #[instrument(skip(self))]
+    pub async fn receive_pack_advertisement(
+        &self,
+        upstream: Uri,
+        auth: Option<HeaderValue>,
+        protocol: Option<String>,
+    ) -> Result<UpstreamResponse> {
+        let request = client()
+            .get(format!("{upstream}/info/refs?service=git-receive-pack"))
+            .headers(proxy_headers(auth, protocol)?);
+
+        relay(request, "/info/refs").await
     }
+
+    #[instrument(skip(self, input))]
+    pub async fn receive_pack(
+        &self,
+        upstream: Uri,
+        auth: Option<HeaderValue>,
+        protocol: Option<String>,
+        input: Body,
+    ) -> Result<UpstreamResponse> {
+        let request = client()
+            .post(format!("{upstream}/git-receive-pack"))
+            .headers(proxy_headers(auth, protocol)?)
+            .header(
+                header::CONTENT_TYPE,
+                "application/x-git-receive-pack-request",
+            )
+            .header(header::ACCEPT, "application/x-git-receive-pack-result")
+            // Axum bodies aren't `Sync`, but they're only ever polled through `&mut` anyways.
+            .body(reqwest::Body::wrap_stream(SyncStream::new(
+                input.into_data_stream(),
+            )));
+
+        relay(request, "git-receive-pack").await
+    }
+
+    #[instrument(skip(self, input))]
+    pub async fn lfs_batch(
+        &self,
+        upstream: Uri,
+        auth: Option<HeaderValue>,
+        input: Bytes,
+    ) -> Result<UpstreamResponse> {
+        let request = client()
+            .post(format!("{upstream}/info/lfs/objects/batch"))
+            .headers(proxy_headers(auth, None)?)
+            .header(header::CONTENT_TYPE, lfs::MEDIA_TYPE)
+            .header(header::ACCEPT, lfs::MEDIA_TYPE)
+            .body(input);
+
+        relay(request, "LFS batch").await
+    }
+
+    #[instrument(skip(self, action), fields(href = action.href))]
+    pub async fn lfs_download(&self, action: lfs::Action) -> Result<GitAsyncRead> {
+        let mut request = client().get(action.href);
+
+        for (name, value) in action.header {
+            let mut value = HeaderValue::try_from(value)
+                .context("invalid header in LFS download action from upstream")?;
+            value.set_sensitive(true);
+            request = request.header(name, value);
+        }
+
+        let response = request
+            .send()
+            .await
+            .context("failed to reach upstream for LFS download")?;
+
+        if response.status() != StatusCode::OK {
+            return Err(anyhow!(
+                "upstream responded to LFS download with status {}",
+                response.status()
+            )
+            .into());
+        }
+
+        let stream = response.bytes_stream().map_err(io::Error::other);
+        Ok(Box::new(StreamReader::new(stream)))
+    }
+}
+
+/// A response from the upstream that should be relayed to the client as is.
+#[derive(Debug)]
+pub struct UpstreamResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+fn client() -> Client {
+    Client::builder()
+        .user_agent(APP_NAME)
+        .build()
+        .expect("failed to build reqwest client")
+}
+
+fn proxy_headers(auth: Option<HeaderValue>, protocol: Option<String>) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+
+    if let Some(auth) = auth {
+        assert!(auth.is_sensitive());
+        headers.insert(header::AUTHORIZATION, auth);
+    }
+
+    if let Some(protocol) = protocol {
+        let protocol = HeaderValue::try_from(protocol)
+            .map_err(|_| Error::BadRequest("invalid git-protocol header"))?;
+        headers.insert("git-protocol", protocol);
+    }
+
+    Ok(headers)
+}
+
+async fn relay(request: RequestBuilder, endpoint: &'static str) -> Result<UpstreamResponse> {
+    let response = request
+        .send()
+        .await
+        .with_context(|| format!("failed to reach upstream for {endpoint}"))?;
+
+    let status = response.status();
+
+    // Only relay the headers that matter to git clients, nothing related to the connection with
+    // the upstream itself.
+    let mut headers = HeaderMap::new();
+    for name in [
+        header::CONTENT_TYPE,
+        header::CACHE_CONTROL,
+        header::WWW_AUTHENTICATE,
+    ] {
+        if let Some(value) = response.headers().get(&name) {
+            headers.insert(name, value.clone());
+        }
+    }
+
+    let body = response
+        .bytes()
+        .await
+        .with_context(|| format!("failed to read full response from upstream {endpoint}"))?;
+
+    Ok(UpstreamResponse {
+        status,
+        headers,
+        body,
+    })
 }
 
 fn exited_ok_with_stdout(
@@ -266,78 +671,712 @@ fn exited_ok_with_stdout(
     Ok(output.stdout)
 }
 
-fn parse_smart_refs(input: Bytes) -> anyhow::Result<Option<String>> {
-    let input = std::str::from_utf8(&input)?;
+/// Make `command` run at the lowest CPU and (on Linux) I/O priorities.
+fn low_priority(command: &mut Command) -> &mut Command {
+    #[cfg(target_os = "linux")]
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    #[cfg(target_os = "linux")]
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3 << 13;
+
+    // SAFETY: setpriority and ioprio_set are async-signal-safe.
+    unsafe {
+        command.pre_exec(|| {
+            // Not being able to lower the priorities isn't worth failing over.
+            libc::setpriority(libc::PRIO_PROCESS, 0, 19);
+            #[cfg(target_os = "linux")]
+            libc::syscall(
+                libc::SYS_ioprio_set,
+                IOPRIO_WHO_PROCESS,
+                0,
+                IOPRIO_CLASS_IDLE,
+            );
+            Ok(())
+        })
+    }
+}
+
+/// The process groups of the running children, so that they can be stopped on shutdown.
+static RUNNING: Mutex<BTreeSet<libc::pid_t>> = Mutex::new(BTreeSet::new());
+
+/// Set (with `RUNNING` locked) once `kill_children` is called, from when on no more children are
+/// spawned.
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// How often to check whether the children have exited, after signaling them.
+const EXIT_CHECK_INTERVAL: Duration = Duration::from_millis(50);
 
-    let Some((header, ref_list)) = input.split_once("0000") else {
-        bail!("missing flush packet");
+/// A child registered in `RUNNING`, until dropped (which should only happen once it's reaped).
+struct Running(libc::pid_t);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Spawn a child.
+///
+/// It gets its own process group, so that any processes it starts in turn (like
+/// `git-pack-objects`) can be killed along with it. This also keeps signals meant for the server
+/// (like a Ctrl-C in a terminal) from interrupting it, as stopping children is left to
+/// `kill_children`, which also makes this refuse to spawn any more children.
+fn spawn(command: &mut Command, process_name: &'static str) -> Result<(Child, Running)> {
+    for var in systemd::LISTEN_ENV {
+        command.env_remove(var);
+    }
+
+    // SAFETY: setpgid is async-signal-safe. (`Command::process_group` is still unstable in tokio.)
+    unsafe {
+        command.pre_exec(|| match libc::setpgid(0, 0) {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        });
+    }
+
+    let mut running = RUNNING.lock().unwrap();
+    if STOPPING.load(Ordering::Relaxed) {
+        return Err(anyhow!("shutting down, not spawning `{process_name}`").into());
+    }
+    let child = command
+        .spawn()
+        .unwrap_or_else(|err| panic!("failed to spawn `{process_name}`: {err}"));
+    let pgid = child.id().expect("child should not have been waited for") as libc::pid_t;
+    running.insert(pgid);
+
+    Ok((child, Running(pgid)))
+}
+
+/// Run `command` to completion, collecting its output.
+///
+/// The child is waited for in a separate task, so that it's still reaped (and tracked until then)
+/// if the caller gives up on it.
+async fn run(command: &mut Command, process_name: &'static str) -> Result<Output> {
+    let (child, running) = spawn(
+        command.stdout(Stdio::piped()).stderr(Stdio::piped()),
+        process_name,
+    )?;
+
+    let output = tokio::spawn(async move {
+        let output = child.wait_with_output().await;
+        drop(running);
+        output
+    })
+    .await
+    .expect("child task should not panic")
+    .unwrap_or_else(|err| panic!("failed to wait for `{process_name}`: {err}"));
+    Ok(output)
+}
+
+/// How many children are currently running.
+pub fn running_children() -> usize {
+    RUNNING.lock().unwrap().len()
+}
+
+/// Stop all running children (and their process groups), giving them `timeout` to exit cleanly
+/// before killing them outright.
+///
+/// Returns whether all of them have exited (and been reaped).
+pub async fn kill_children(timeout: Duration) -> bool {
+    let signal = |signal| {
+        let running = RUNNING.lock().unwrap();
+        STOPPING.store(true, Ordering::Relaxed);
+        for pgid in running.iter() {
+            // SAFETY: killpg has no memory safety implications. And registered children haven't
+            // been reaped yet, so their pids (which are also their pgids) can't have been reused.
+            unsafe { libc::killpg(*pgid, signal) };
+        }
+        running.len()
+    };
+    let exited = |timeout| async move {
+        let deadline = Instant::now() + timeout;
+        while !RUNNING.lock().unwrap().is_empty() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(EXIT_CHECK_INTERVAL).await;
+        }
+        true
     };
 
-    if header.contains("version 2") {
-        bail!("smart protocol v2 is not supported");
+    let children = signal(libc::SIGTERM);
+    if children == 0 {
+        return true;
+    }
+    tracing::info!(children, "terminating running children");
+    if exited(timeout).await {
+        return true;
     }
 
-    // Some upstrams (e.g. GitHub) return no ref-list istead of the "empty" ref-list with a single
-    // zero-id entry.
-    if ref_list == "0000" {
-        return Ok(None);
+    let children = signal(libc::SIGKILL);
+    tracing::warn!(children, "children still running, killed them");
+    exited(timeout).await
+}
+
+/// Hand off the stdout of `child`, tying the child to its lifetime.
+///
+/// The stdout output will be handed off to axum to transmit it to the client. Therefore, spawn a
+/// separete task to wait for and reape the child process when its done, instead of relying on
+/// tokio doing that on a best-effort-only basis. This also allow us to log any errors, and to hold
+/// on to `keep_alive` for exactly as long as the child runs.
+///
+/// If the output is dropped before being read to the end, which happens when the client
+/// disconnects, the child and its process group are killed right away.
+fn supervise(
+    mut child: Child,
+    running: Running,
+    process_name: &'static str,
+    keep_alive: KeepAlive,
+) -> GitAsyncRead {
+    let stdout = child.stdout.take().expect("stdout should be piped");
+    let mut stderr = child.stderr.take().expect("stderr should be piped");
+    let pgid = child.id().expect("child should not have been waited for") as libc::pid_t;
+    let (cancel, cancelled) = oneshot::channel();
+
+    tokio::spawn(
+        async move {
+            let exited = async {
+                let mut output = Vec::new();
+                let _ = stderr.read_to_end(&mut output).await;
+                (child.wait().await, output)
+            };
+            tokio::pin!(exited);
+
+            let (status, stderr, killed) = tokio::select! {
+                (status, stderr) = &mut exited => (status, stderr, false),
+                Ok(()) = cancelled => {
+                    // SAFETY: killpg has no memory safety implications. And the child hasn't been
+                    // reaped yet, so its pid (which is also its pgid) can't have been reused.
+                    unsafe { libc::killpg(pgid, libc::SIGTERM) };
+                    let (status, stderr) = exited.await;
+                    (status, stderr, true)
+                }
+            };
+
+            let status = status
+                .unwrap_or_else(|err| panic!("failed to wait for `{process_name}` to exit: {err}"));
+            if killed {
+                tracing::info!(
+                    status = status.into_raw(),
+                    "client went away, killed `{process_name}`",
+                );
+            } else if !status.success() {
+                tracing::error!(
+                    status = status.into_raw(),
+                    stderr = ?Bytes::from(stderr),
+                    "`{process_name}` exited with non-zero status",
+                );
+            } else {
+                tracing::trace!("`{process_name}` exited with 0");
+            }
+            drop(running);
+            drop(keep_alive);
+        }
+        .in_current_span(),
+    );
+
+    Box::new(ChildOutput {
+        stdout,
+        cancel: Some(cancel),
+    })
+}
+
+/// The stdout of a supervised child, which cancels the child if dropped before EOF.
+struct ChildOutput {
+    stdout: ChildStdout,
+    cancel: Option<oneshot::Sender<()>>,
+}
+
+impl AsyncRead for ChildOutput {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.stdout).poll_read(cx, buf);
+
+        if matches!(poll, Poll::Ready(Ok(())))
+            && buf.filled().len() == filled
+            && buf.remaining() > 0
+        {
+            // EOF, nothing left to cancel.
+            self.cancel = None;
+        }
+
+        poll
     }
+}
 
-    let Some((first_item, _)) = ref_list.split_once('\n') else {
-        bail!("ref-list should be LF separated");
+impl Drop for ChildOutput {
+    fn drop(&mut self) {
+        if let Some(cancel) = self.cancel.take() {
+            let _ = cancel.send(());
+        }
+    }
+}
+
+fn check_upstream_response(
+    response: &Response,
+    endpoint: &'static str,
+    expected_content_type: &'static str,
+) -> Result<()> {
+    match response.status() {
+        StatusCode::OK => { /* keep going */ }
+        StatusCode::NOT_FOUND => return Err(Error::NotFound),
+        StatusCode::UNAUTHORIZED => {
+            let authenticate = response
+                .headers()
+                .get(header::WWW_AUTHENTICATE)
+                .cloned()
+                .ok_or(anyhow!(
+                    "missing WWW-Authenticate header for 401 Unauthorized response from upstream"
+                ))?;
+            return Err(Error::MissingAuth(authenticate));
+        }
+        code if code.is_server_error() => {
+            return Err(Error::UpstreamUnavailable(anyhow!(
+                "upstream responded to {endpoint} with status {code}"
+            )))
+        }
+        code => return Err(anyhow!("upstream responded to {endpoint} with status {code}").into()),
+    };
+
+    let content_type = response.headers().get(header::CONTENT_TYPE);
+    if !matches!(content_type, Some(v) if v == expected_content_type) {
+        return Err(anyhow!(
+            "upstream response to {endpoint} doesn't match smart protocol: {content_type:?}"
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+fn unavailable(err: reqwest::Error, context: &'static str) -> Error {
+    Error::UpstreamUnavailable(anyhow::Error::new(err).context(context))
+}
+
+/// Whether `git fetch` failed because of the upstream, going by its `stderr`.
+///
+/// Git reports both connection failures and HTTP errors as "unable to access"; of the latter,
+/// client errors (like 401 or 404) are excluded.
+fn fetch_failed_on_upstream(stderr: &[u8]) -> bool {
+    let stderr = String::from_utf8_lossy(stderr);
+    stderr.contains("unable to access") && !stderr.contains("The requested URL returned error: 4")
+}
+
+/// A stream of pkt-lines, as used by the smart protocol.
+///
+/// Special packets (flush, delimiter and response end) are returned as `None`.
+struct PktLines<'a>(&'a [u8]);
+
+impl<'a> PktLines<'a> {
+    fn read(&mut self) -> anyhow::Result<Option<&'a str>> {
+        let result = self.read_inner();
+        if result.is_err() {
+            // Don't try to make sense of anything after a malformed packet.
+            self.0 = &[];
+        }
+        result
+    }
+
+    fn read_inner(&mut self) -> anyhow::Result<Option<&'a str>> {
+        ensure!(self.0.len() >= 4, "unexpected end of pkt-line stream");
+
+        let (len, rest) = self.0.split_at(4);
+        let len = std::str::from_utf8(len)
+            .ok()
+            .and_then(|len| usize::from_str_radix(len, 16).ok())
+            .context("invalid pkt-line length")?;
+
+        if len < 4 {
+            self.0 = rest;
+            return Ok(None);
+        }
+
+        ensure!(len <= self.0.len(), "truncated pkt-line");
+        let payload = std::str::from_utf8(&self.0[4..len])?;
+        self.0 = &self.0[len..];
+
+        Ok(Some(payload))
+    }
+}
+
+/// The relevant parts of an upstream `/info/refs` advertisement.
+#[derive(Debug, PartialEq)]
+enum Advertisement {
+    /// Protocol v0 or v1, which advertises all refs.
+    V0(Refs),
+    /// Protocol v2, which only advertises capabilities.
+    V2,
+}
+
+fn parse_smart_refs(input: Bytes) -> anyhow::Result<Advertisement> {
+    let mut pkts = PktLines(&input);
+
+    // Skip the service header. Not all v2 upstreams send it.
+    let mut pkt = pkts.read()?;
+    if pkt.is_some_and(|line| line.starts_with("# service=")) {
+        if pkts.read()?.is_some() {
+            bail!("missing flush packet");
+        }
+        pkt = pkts.read()?;
+    }
+
+    match pkt.map(str::trim_end) {
+        Some("version 2") => return Ok(Advertisement::V2),
+        Some("version 1") => pkt = pkts.read()?,
+        _ => {}
+    }
+
+    let mut refs = Refs::default();
+
+    // Some upstrams (e.g. GitHub) return no ref-list istead of the "empty" ref-list with a single
+    // zero-id entry.
+    let Some(first_item) = pkt else {
+        return Ok(Advertisement::V0(refs));
     };
 
     tracing::debug!(first_item);
 
-    let Some((_, caps)) = first_item.split_once('\0') else {
+    let Some((first_ref, caps)) = first_item.trim_end().split_once('\0') else {
         bail!("first ref-list should include capabilites aftern NUL");
     };
 
     for cap in caps.split(' ') {
         if let Some(symref) = cap.strip_prefix("symref=HEAD:") {
-            return Ok(Some(symref.to_string()));
+            refs.head = Some(symref.to_string());
+        }
+    }
+
+    let mut item = Some(first_ref);
+    while let Some(line) = item {
+        let Some((oid, name)) = line.trim_end().split_once(' ') else {
+            bail!("ref-list items should have an object id and a name");
+        };
+        refs.insert_advertised(oid, name);
+        item = pkts.read()?;
+    }
+
+    Ok(Advertisement::V0(refs))
+}
+
+fn parse_ls_refs(input: Bytes) -> anyhow::Result<Refs> {
+    let mut pkts = PktLines(&input);
+    let mut refs = Refs::default();
+
+    while let Some(line) = pkts.read()? {
+        let mut attributes = line.trim_end().split(' ');
+
+        let (Some(oid), Some(name)) = (attributes.next(), attributes.next()) else {
+            bail!("ls-refs response should have an object id and a name per ref");
+        };
+
+        if name == "HEAD" {
+            refs.head = attributes
+                .find_map(|attr| attr.strip_prefix("symref-target:"))
+                .map(String::from);
+        } else {
+            refs.insert_advertised(oid, name);
         }
     }
 
-    Ok(None)
+    Ok(refs)
+}
+
+fn parse_local_refs(input: &[u8]) -> anyhow::Result<BTreeMap<String, String>> {
+    let input = std::str::from_utf8(input)?;
+
+    input
+        .lines()
+        .map(|line| {
+            let (oid, name) = line
+                .split_once(' ')
+                .context("for-each-ref output should have an object id and a name per ref")?;
+            Ok((name.to_string(), oid.to_string()))
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
+    use std::process::Stdio;
+    use std::time::Duration;
+
     use axum::body::Bytes;
+    use axum::http::{StatusCode, Uri};
+    use axum::Router;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+    use tokio::process::Command;
+    use tokio::sync::oneshot;
+
+    use super::{
+        fetch_failed_on_upstream, parse_local_refs, parse_ls_refs, parse_smart_refs, run, spawn,
+        supervise, Advertisement, Git, Refs,
+    };
+    use crate::error::Error;
+
+    #[tokio::test]
+    async fn check() {
+        Git::default().check().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn maintain_empty_repository() {
+        let local = tempfile::tempdir().unwrap().into_path();
+
+        let git = Git::default();
+        git.init(local.clone()).await.unwrap();
+        git.maintain(local).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn verify_reports_problems() {
+        let local = tempfile::tempdir().unwrap().into_path();
 
-    use super::parse_smart_refs;
+        let git = Git::default();
+        git.init(local.clone()).await.unwrap();
+        git.verify(local.clone()).await.unwrap();
+
+        // A ref to a missing object.
+        let missing = "1".repeat(40);
+        std::fs::write(local.join("refs/heads/main"), format!("{missing}\n")).unwrap();
+
+        let err = git.verify(local).await.unwrap_err();
+        let err = format!("{err:#}");
+        assert!(err.starts_with("repository failed verification: "));
+        assert!(err.contains(&missing));
+    }
+
+    #[tokio::test]
+    async fn object_pools() {
+        let pool = tempfile::tempdir().unwrap().into_path().join("pool.git");
+        let local = tempfile::tempdir().unwrap().into_path();
+
+        let git = Git::default();
+        git.init(local.clone()).await.unwrap();
+        assert_eq!(git.root_commit(local.clone()).await.unwrap(), None);
+
+        let commit = |args: &[&str]| {
+            let output = std::process::Command::new("git")
+                .args([
+                    "-c",
+                    "user.name=mock",
+                    "-c",
+                    "user.email=mock@example.com",
+                    "-C",
+                ])
+                .arg(&local)
+                .args(args)
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            String::from_utf8(output.stdout).unwrap().trim().to_string()
+        };
+        let empty_tree = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+        let root = commit(&["commit-tree", "-m", "root", empty_tree]);
+        let head = commit(&["commit-tree", "-m", "head", "-p", &root, empty_tree]);
+        commit(&["update-ref", "refs/heads/main", &head]);
+        commit(&["symbolic-ref", "HEAD", "refs/heads/main"]);
+
+        assert_eq!(git.root_commit(local.clone()).await.unwrap(), Some(root));
+
+        git.sync_pool(pool.clone(), local.clone(), "mock".into())
+            .await
+            .unwrap();
+        assert_eq!(
+            git.local_refs(pool.clone()).await.unwrap(),
+            [("refs/forks/mock/heads/main".into(), head)].into()
+        );
+
+        git.maintain(pool.clone()).await.unwrap();
+
+        git.leave_pool(pool.clone(), "mock".into()).await.unwrap();
+        assert!(git.local_refs(pool).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn children_dont_inherit_socket_activation() {
+        let output = run(
+            Command::new("sh")
+                .args(["-c", "echo ${LISTEN_FDS-unset}"])
+                .env("LISTEN_FDS", "1"),
+            "sh",
+        )
+        .await
+        .unwrap();
+        assert_eq!(output.stdout, b"unset\n");
+    }
+
+    #[tokio::test]
+    async fn kill_child_when_output_is_dropped() {
+        let (child, running) = spawn(
+            Command::new("sh")
+                .args(["-c", "echo ready && sleep 60"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()),
+            "sh",
+        )
+        .unwrap();
+
+        // Dropped only once the child exits.
+        let (keep_alive, exited) = oneshot::channel::<()>();
+
+        let mut output = supervise(child, running, "sh", Box::new(keep_alive));
+        let mut ready = [0; 6];
+        output.read_exact(&mut ready).await.unwrap();
+        assert_eq!(&ready, b"ready\n");
+        drop(output);
+
+        tokio::time::timeout(Duration::from_secs(10), exited)
+            .await
+            .expect("child should have been killed")
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn fetch_failures() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let upstream = Router::new().fallback(|uri: Uri| async move {
+            match uri.path().starts_with("/missing/") {
+                true => StatusCode::NOT_FOUND,
+                false => StatusCode::SERVICE_UNAVAILABLE,
+            }
+        });
+        tokio::spawn(async move { axum::serve(listener, upstream).await });
+
+        let local = tempfile::tempdir().unwrap().into_path();
+        let git = Git::default();
+        git.init(local.clone()).await.unwrap();
+
+        let fetch = |path: &str| {
+            let upstream = format!("http://{address}/{path}").parse().unwrap();
+            git.fetch(upstream, local.clone(), None)
+        };
+        assert!(matches!(
+            fetch("unavailable/a").await,
+            Err(Error::UpstreamUnavailable(_))
+        ));
+        assert!(matches!(fetch("missing/a").await, Err(Error::Other(_))));
+    }
+
+    #[test]
+    fn classify_fetch_failures() {
+        assert!(fetch_failed_on_upstream(
+            b"fatal: unable to access 'https://example.com/a/b/c/': Could not resolve host: \
+            example.com\n"
+        ));
+        assert!(fetch_failed_on_upstream(
+            b"fatal: unable to access 'https://example.com/a/b/c/': The requested URL returned \
+            error: 503\n"
+        ));
+        assert!(!fetch_failed_on_upstream(
+            b"fatal: unable to access 'https://example.com/a/b/c/': The requested URL returned \
+            error: 403\n"
+        ));
+        assert!(!fetch_failed_on_upstream(
+            b"error: unable to create temporary file: No space left on device\n"
+        ));
+    }
 
     #[test]
     fn parse_info_refs_response() {
+        let Advertisement::V0(refs) = parse_smart_refs(Bytes::from_static(include_bytes!(
+            "../doc/example-info-refs-response"
+        )))
+        .unwrap() else {
+            panic!("should be a v0 advertisement");
+        };
+
+        assert_eq!(refs.head.as_deref(), Some("refs/heads/master"));
+        assert_eq!(refs.refs.len(), 14);
+        assert_eq!(
+            refs.refs["refs/heads/master"],
+            "e22660e40203ffe4a3f24ebba616529d92a6d085"
+        );
+        assert_eq!(
+            refs.refs["refs/tags/v0.0.2"],
+            "1ed3c78812b1340178b5bc4eea6009bc22f612f2"
+        );
+    }
+
+    #[test]
+    fn parse_info_refs_response_with_version() {
+        let Advertisement::V0(refs) = parse_smart_refs(Bytes::from_static(include_bytes!(
+            "../doc/example-info-refs-response-with-version"
+        )))
+        .unwrap() else {
+            panic!("should be a v0 advertisement");
+        };
+
+        assert_eq!(refs.head.as_deref(), Some("refs/heads/master"));
+        assert_eq!(
+            refs.refs["refs/heads/master"],
+            "e22660e40203ffe4a3f24ebba616529d92a6d085"
+        );
+    }
+
+    #[test]
+    fn parse_empty_repo_info_refs_response() {
         assert_eq!(
             parse_smart_refs(Bytes::from_static(include_bytes!(
-                "../doc/example-info-refs-response"
+                "../doc/example-empty-info-refs-response"
             )))
             .unwrap(),
-            Some(String::from("refs/heads/master"))
+            Advertisement::V0(Refs::default())
         );
     }
 
     #[test]
-    fn parse_info_refs_response_with_version() {
+    fn parse_v2_info_refs_response() {
         assert_eq!(
             parse_smart_refs(Bytes::from_static(include_bytes!(
-                "../doc/example-info-refs-response-with-version"
+                "../doc/example-info-refs-response-v2"
             )))
             .unwrap(),
-            Some(String::from("refs/heads/master"))
+            Advertisement::V2
         );
     }
 
     #[test]
-    fn parse_empty_repo_info_refs_response() {
+    fn parse_ls_refs_response() {
         assert_eq!(
-            parse_smart_refs(Bytes::from_static(include_bytes!(
-                "../doc/example-empty-info-refs-response"
+            parse_ls_refs(Bytes::from_static(include_bytes!(
+                "../doc/example-ls-refs-response"
             )))
             .unwrap(),
-            None
+            Refs {
+                head: Some(String::from("refs/heads/master")),
+                refs: [(
+                    String::from("refs/heads/master"),
+                    String::from("19df417b6b62ad3b4c22859971bfd8777992b008")
+                )]
+                .into(),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_for_each_ref_output() {
+        assert_eq!(
+            parse_local_refs(
+                b"19df417b6b62ad3b4c22859971bfd8777992b008 refs/heads/master\n\
+                1ed3c78812b1340178b5bc4eea6009bc22f612f2 refs/tags/v0.0.2\n"
+            )
+            .unwrap(),
+            [
+                (
+                    String::from("refs/heads/master"),
+                    String::from("19df417b6b62ad3b4c22859971bfd8777992b008")
+                ),
+                (
+                    String::from("refs/tags/v0.0.2"),
+                    String::from("1ed3c78812b1340178b5bc4eea6009bc22f612f2")
+                ),
+            ]
+            .into()
         );
     }
 }
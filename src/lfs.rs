@@ -0,0 +1,409 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, Context};
+use axum::http::HeaderName;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use crate::error::{Error, Result};
+
+pub const MEDIA_TYPE: &str = "application/vnd.git-lfs+json";
+
+/// Request header with which clients redeem the download grants we hand out in batch responses.
+pub const GRANT_HEADER: HeaderName = HeaderName::from_static("git-cache-lfs-grant");
+
+/// Upper bound for how long a download grant is valid, regardless of what the upstream allows.
+const MAX_GRANT_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Download,
+    Upload,
+}
+
+/// The part of a batch request that we care about; the rest is forwarded to the upstream as is.
+#[derive(Debug, Deserialize)]
+pub struct BatchRequest {
+    pub operation: Operation,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BatchResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transfer: Option<String>,
+    pub objects: Vec<ObjectResponse>,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ObjectResponse {
+    pub oid: String,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actions: Option<Actions>,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Actions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download: Option<Action>,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Action {
+    pub href: String,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub header: HashMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_in: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<String>,
+}
+
+impl Action {
+    /// How much longer the upstream will honor this action, if it told us.
+    fn ttl(&self) -> Option<Duration> {
+        let expires_in = self
+            .expires_in
+            .map(|secs| Duration::from_secs(secs.max(0) as u64));
+
+        let expires_at = self.expires_at.as_deref().map(|at| {
+            humantime::parse_rfc3339_weak(at)
+                .ok()
+                .and_then(|at| at.duration_since(SystemTime::now()).ok())
+                .unwrap_or_default()
+        });
+
+        expires_in.into_iter().chain(expires_at).min()
+    }
+}
+
+#[derive(Debug)]
+struct Grant {
+    oid: String,
+    size: u64,
+    upstream: Action,
+    expires: Instant,
+}
+
+/// A permission to download an object, issued after the upstream authorized a batch request.
+#[derive(Debug, PartialEq)]
+pub struct Download {
+    pub size: u64,
+    pub upstream: Action,
+}
+
+/// Content-addressed storage for LFS objects, shared by all repositories.
+#[derive(Debug)]
+pub struct Store {
+    root: PathBuf,
+    grants: Mutex<HashMap<String, Grant>>,
+}
+
+impl Store {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            grants: Default::default(),
+        }
+    }
+
+    /// Redirect all downloads in an upstream batch response to `objects_url` (our own
+    /// `.../info/lfs/objects` endpoint), issuing the grants necessary to redeem them.
+    pub fn cache_downloads(&self, batch: &mut BatchResponse, objects_url: &str) {
+        if batch.transfer.as_deref().is_some_and(|t| t != "basic") {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut grants = self.grants.lock().unwrap();
+        grants.retain(|_, grant| grant.expires > now);
+
+        for object in &mut batch.objects {
+            let Some(download) = object.actions.as_mut().and_then(|a| a.download.as_mut()) else {
+                continue;
+            };
+
+            if object_path(&self.root, &object.oid).is_none() {
+                tracing::warn!(oid = object.oid, "not caching object with invalid oid");
+                continue;
+            }
+
+            let ttl = download.ttl().unwrap_or(MAX_GRANT_TTL).min(MAX_GRANT_TTL);
+            let token = Uuid::new_v4().to_string();
+
+            let upstream = std::mem::replace(
+                download,
+                Action {
+                    href: format!("{objects_url}/{}", object.oid),
+                    header: HashMap::from([(GRANT_HEADER.to_string(), token.clone())]),
+                    expires_in: Some(ttl.as_secs() as i64),
+                    expires_at: None,
+                },
+            );
+
+            grants.insert(
+                token,
+                Grant {
+                    oid: object.oid.clone(),
+                    size: object.size,
+                    upstream,
+                    expires: now + ttl,
+                },
+            );
+        }
+    }
+
+    pub fn redeem(&self, token: &str, oid: &str) -> Option<Download> {
+        let grants = self.grants.lock().unwrap();
+        let grant = grants.get(token)?;
+
+        if grant.oid != oid || grant.expires <= Instant::now() {
+            return None;
+        }
+
+        Some(Download {
+            size: grant.size,
+            upstream: grant.upstream.clone(),
+        })
+    }
+
+    pub async fn open(&self, oid: &str) -> Result<Option<File>> {
+        let path = object_path(&self.root, oid).ok_or(Error::NotFound)?;
+
+        match File::open(path).await {
+            Ok(file) => Ok(Some(file)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(anyhow::Error::new(err)
+                .context("failed to open LFS object")
+                .into()),
+        }
+    }
+
+    /// Start storing an object whose content is written incrementally, e.g. while it's also being
+    /// sent to a client.
+    pub async fn begin(&self, oid: &str, size: u64) -> Result<Pending> {
+        let path = object_path(&self.root, oid).ok_or(Error::NotFound)?;
+
+        let tmp_dir = self.root.join("tmp");
+        fs::create_dir_all(&tmp_dir)
+            .await
+            .context("failed to create LFS temporary directory")?;
+
+        let tmp = tmp_dir.join(Uuid::new_v4().to_string());
+        let file = File::create(&tmp)
+            .await
+            .context("failed to create LFS temporary file")?;
+
+        Ok(Pending {
+            oid: oid.to_string(),
+            size,
+            path,
+            tmp,
+            file,
+            hasher: Sha256::new(),
+            written: 0,
+        })
+    }
+}
+
+/// An object being stored, kept in a temporary file until it's been verified. Dropping it before
+/// [`Pending::commit`] discards the temporary file.
+#[derive(Debug)]
+pub struct Pending {
+    oid: String,
+    size: u64,
+    path: PathBuf,
+    tmp: PathBuf,
+    file: File,
+    hasher: Sha256,
+    written: u64,
+}
+
+impl Pending {
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<()> {
+        self.hasher.update(chunk);
+        self.file
+            .write_all(chunk)
+            .await
+            .context("failed to write LFS object")?;
+        self.written += chunk.len() as u64;
+        Ok(())
+    }
+
+    /// Move the object into place, if everything written really is its content.
+    pub async fn commit(mut self) -> Result<()> {
+        self.file
+            .flush()
+            .await
+            .context("failed to write LFS object")?;
+
+        let (oid, size, written) = (&self.oid, self.size, self.written);
+        let actual = format!("{:x}", self.hasher.finalize_reset());
+        if written != size || actual != *oid {
+            return Err(anyhow!(
+                "LFS object from upstream doesn't match, expected {oid} ({size} bytes) but got \
+                {actual} ({written} bytes)"
+            )
+            .into());
+        }
+
+        let parent = self
+            .path
+            .parent()
+            .expect("object path should have a parent");
+        fs::create_dir_all(parent)
+            .await
+            .context("failed to create LFS object directory")?;
+        fs::rename(&self.tmp, &self.path)
+            .await
+            .context("failed to move LFS object into place")?;
+
+        Ok(())
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        // Already gone if it was committed.
+        let _ = std::fs::remove_file(&self.tmp);
+    }
+}
+
+/// Where an object is stored, using the same layout as the git-lfs local storage. Returns `None`
+/// for anything that isn't a SHA-256 hex digest.
+fn object_path(root: &Path, oid: &str) -> Option<PathBuf> {
+    if oid.len() != 64 || !oid.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+
+    Some(
+        root.join("objects")
+            .join(&oid[0..2])
+            .join(&oid[2..4])
+            .join(oid),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    // `printf "mock lfs object" | sha256sum`
+    const OID: &str = "cb27cb29a4aad7f769e7a355fadf5a859c9335172e231af46a8a65fcfe1e67ca";
+
+    #[test]
+    fn object_path_sanitization() {
+        let root = PathBuf::from("/cache/.lfs");
+
+        assert_eq!(
+            object_path(&root, OID),
+            Some(root.join("objects/cb/27").join(OID))
+        );
+
+        assert_eq!(object_path(&root, "../../../etc/passwd"), None);
+        assert_eq!(object_path(&root, &OID.to_uppercase()), None);
+        assert_eq!(object_path(&root, &OID[1..]), None);
+    }
+
+    #[tokio::test]
+    async fn insert_verifies_content() {
+        let store = Store::new(tempdir().unwrap().into_path());
+
+        let mut pending = store.begin(OID, 15).await.unwrap();
+        pending.write(b"mock lfs ").await.unwrap();
+        pending.write(b"objecT").await.unwrap();
+        assert!(pending.commit().await.is_err());
+        assert!(store.open(OID).await.unwrap().is_none());
+
+        // Nor is an incomplete object stored.
+        let mut pending = store.begin(OID, 15).await.unwrap();
+        pending.write(b"mock lfs").await.unwrap();
+        drop(pending);
+        assert!(store.open(OID).await.unwrap().is_none());
+
+        let mut pending = store.begin(OID, 15).await.unwrap();
+        pending.write(b"mock lfs ").await.unwrap();
+        pending.write(b"object").await.unwrap();
+        pending.commit().await.unwrap();
+        assert!(store.open(OID).await.unwrap().is_some());
+
+        // Temporary files don't linger either way.
+        let tmp = std::fs::read_dir(store.root.join("tmp")).unwrap();
+        assert_eq!(tmp.count(), 0);
+    }
+
+    #[test]
+    fn grants() {
+        let store = Store::new(PathBuf::from("/cache/.lfs"));
+
+        let mut batch: BatchResponse = serde_json::from_value(serde_json::json!({
+            "objects": [{
+                "oid": OID,
+                "size": 15,
+                "actions": {
+                    "download": {
+                        "href": "https://lfs.example.com/mock",
+                        "header": { "Authorization": "mock upstream auth" },
+                        "expires_in": 60
+                    }
+                }
+            }]
+        }))
+        .unwrap();
+
+        store.cache_downloads(
+            &mut batch,
+            "http://cache/example.com/a/b/c.git/info/lfs/objects",
+        );
+
+        let download = batch.objects[0]
+            .actions
+            .as_ref()
+            .unwrap()
+            .download
+            .as_ref()
+            .unwrap();
+
+        assert_eq!(
+            download.href,
+            format!("http://cache/example.com/a/b/c.git/info/lfs/objects/{OID}")
+        );
+        assert_eq!(download.expires_in, Some(60));
+
+        let token = &download.header[GRANT_HEADER.as_str()];
+
+        assert_eq!(store.redeem(token, &"0".repeat(64)), None);
+        assert_eq!(store.redeem("mock token", OID), None);
+        assert_eq!(
+            store.redeem(token, OID),
+            Some(Download {
+                size: 15,
+                upstream: Action {
+                    href: String::from("https://lfs.example.com/mock"),
+                    header: HashMap::from([(
+                        String::from("Authorization"),
+                        String::from("mock upstream auth")
+                    )]),
+                    expires_in: Some(60),
+                    expires_at: None,
+                }
+            })
+        );
+    }
+}
@@ -1,6 +1,18 @@
+mod admin;
+pub mod cli;
+mod config;
 mod error;
 mod git;
+mod lfs;
+mod listen;
+pub mod lock;
+mod metrics;
+mod mirror;
+mod pool;
 mod repo;
 pub mod server;
+mod shutdown;
+mod systemd;
+mod tls;
 
 const APP_NAME: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
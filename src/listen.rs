@@ -0,0 +1,462 @@
+//! Listening for connections on TCP and Unix domain sockets.
+
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use axum::Router;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::service::TowerToHyperService;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+use tokio_rustls::TlsAcceptor;
+
+use crate::shutdown::Shutdown;
+
+/// An address to listen on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Address {
+    /// `host:port` or `[v6]:port`, where host may also be a name to resolve.
+    Tcp(String),
+    /// `unix:/path`.
+    Unix(PathBuf),
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("missing socket path".into());
+            }
+            return Ok(Self::Unix(path.into()));
+        }
+
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or("expected HOST:PORT, [IPV6]:PORT or unix:PATH")?;
+        if host.is_empty() {
+            return Err("missing host".into());
+        }
+        if host.contains(':') && !(host.starts_with('[') && host.ends_with(']')) {
+            return Err("IPv6 addresses must be enclosed in brackets".into());
+        }
+        port.parse::<u16>()
+            .map_err(|_| format!("invalid port: {port}"))?;
+
+        Ok(Self::Tcp(s.to_string()))
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(address) => f.write_str(address),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// How Unix domain sockets are set up.
+#[derive(Clone, Debug, Default)]
+pub struct UnixOptions {
+    pub mode: Option<u32>,
+    pub owner: Option<Owner>,
+}
+
+/// A socket owner, as a user and/or group.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Owner {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+/// Parse an octal file mode, e.g. `660`.
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("invalid file mode: {s}"))
+}
+
+/// Parse `USER`, `USER:GROUP` or `:GROUP`, by name or by numeric id.
+pub fn parse_owner(s: &str) -> Result<Owner, String> {
+    let (user, group) = s.split_once(':').unwrap_or((s, ""));
+    let owner = Owner {
+        uid: (!user.is_empty()).then(|| uid(user)).transpose()?,
+        gid: (!group.is_empty()).then(|| gid(group)).transpose()?,
+    };
+    if owner.uid.is_none() && owner.gid.is_none() {
+        return Err("expected USER, USER:GROUP or :GROUP".into());
+    }
+    Ok(owner)
+}
+
+fn uid(user: &str) -> Result<u32, String> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+    let name = CString::new(user).map_err(|_| format!("invalid user: {user}"))?;
+    // SAFETY: `name` is a valid C string, and the result is read right away (nothing else calls
+    // `getpwnam` concurrently, as this only runs while parsing options).
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(format!("unknown user: {user}"));
+    }
+    Ok(unsafe { (*passwd).pw_uid })
+}
+
+fn gid(group: &str) -> Result<u32, String> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).map_err(|_| format!("invalid group: {group}"))?;
+    // SAFETY: see `uid`.
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(format!("unknown group: {group}"));
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Bind to `address`.
+    ///
+    /// Stale Unix domain sockets (e.g. left over by a previous run) are replaced.
+    pub async fn bind(address: &Address, unix: &UnixOptions) -> io::Result<Self> {
+        let path = match address {
+            Address::Tcp(address) => return Ok(Self::Tcp(TcpListener::bind(address).await?)),
+            Address::Unix(path) => path,
+        };
+
+        match tokio::fs::symlink_metadata(path).await {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                tokio::fs::remove_file(path).await?
+            }
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{path:?} exists and is not a socket"),
+                ))
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        let listener = UnixListener::bind(path)?;
+
+        if let Some(mode) = unix.mode {
+            tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).await?;
+        }
+        if let Some(owner) = &unix.owner {
+            std::os::unix::fs::chown(path, owner.uid, owner.gid)?;
+        }
+
+        Ok(Self::Unix(listener, path.clone()))
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(listener) => match listener.local_addr() {
+                Ok(address) => write!(f, "{address}"),
+                Err(_) => f.write_str("(unknown TCP address)"),
+            },
+            Self::Unix(_, path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Serve `router` on all connections accepted by `listener`, until `shutdown` starts.
+///
+/// With `tls`, connections are expected to start with a TLS handshake. Connections still open
+/// when this returns are closed once their in-flight requests are done.
+pub async fn serve(
+    listener: Listener,
+    router: Router,
+    tls: Option<TlsAcceptor>,
+    shutdown: Shutdown,
+) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = accept(&listener, &router, &tls, &shutdown) => accepted,
+            _ = shutdown.started() => break,
+        };
+
+        if let Err(err) = accepted {
+            // Most likely out of file descriptors, so back off for a bit.
+            tracing::error!(error = ?err, "failed to accept connection on {listener}");
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+}
+
+async fn accept(
+    listener: &Listener,
+    router: &Router,
+    tls: &Option<TlsAcceptor>,
+    shutdown: &Shutdown,
+) -> io::Result<()> {
+    match listener {
+        Listener::Tcp(listener) => listener.accept().await.map(|(stream, _)| {
+            serve_connection(stream, router.clone(), tls.clone(), shutdown.clone())
+        }),
+        Listener::Unix(listener, _) => listener.accept().await.map(|(stream, _)| {
+            serve_connection(stream, router.clone(), tls.clone(), shutdown.clone())
+        }),
+    }
+}
+
+fn serve_connection<I>(io: I, router: Router, tls: Option<TlsAcceptor>, shutdown: Shutdown)
+where
+    I: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    shutdown.clone().track(async move {
+        let Some(tls) = tls else {
+            return serve_http(io, router, shutdown).await;
+        };
+        match tls.accept(io).await {
+            Ok(io) => serve_http(io, router, shutdown).await,
+            Err(err) => tracing::debug!(error = ?err, "TLS handshake failed"),
+        }
+    });
+}
+
+async fn serve_http<I>(io: I, router: Router, shutdown: Shutdown)
+where
+    I: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let service = TowerToHyperService::new(router);
+    let builder = Builder::new(TokioExecutor::new());
+    let connection = builder.serve_connection_with_upgrades(TokioIo::new(io), service);
+    tokio::pin!(connection);
+
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = shutdown.started() => {
+            // Finish the requests in flight (if any), but don't take any more.
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+
+    if let Err(err) = result {
+        tracing::debug!(error = ?err, "connection closed with an error");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::get;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    use super::*;
+
+    #[test]
+    fn address_parsing() {
+        assert_eq!(
+            "127.0.0.1:8080".parse(),
+            Ok(Address::Tcp("127.0.0.1:8080".into()))
+        );
+        assert_eq!(
+            "localhost:8080".parse(),
+            Ok(Address::Tcp("localhost:8080".into()))
+        );
+        assert_eq!("[::1]:8080".parse(), Ok(Address::Tcp("[::1]:8080".into())));
+        assert_eq!(
+            "unix:/run/git-cache.sock".parse(),
+            Ok(Address::Unix("/run/git-cache.sock".into()))
+        );
+        assert!("::1:8080".parse::<Address>().is_err());
+        assert!("localhost".parse::<Address>().is_err());
+        assert!("localhost:http".parse::<Address>().is_err());
+        assert!(":8080".parse::<Address>().is_err());
+        assert!("unix:".parse::<Address>().is_err());
+    }
+
+    #[test]
+    fn owner_and_mode_parsing() {
+        assert_eq!(parse_mode("660"), Ok(0o660));
+        assert!(parse_mode("8").is_err());
+        assert!(parse_mode("17777").is_err());
+
+        assert_eq!(
+            parse_owner("root"),
+            Ok(Owner {
+                uid: Some(0),
+                gid: None
+            })
+        );
+        assert_eq!(
+            parse_owner("1000:0"),
+            Ok(Owner {
+                uid: Some(1000),
+                gid: Some(0)
+            })
+        );
+        assert_eq!(
+            parse_owner(":1000"),
+            Ok(Owner {
+                uid: None,
+                gid: Some(1000)
+            })
+        );
+        assert!(parse_owner(":").is_err());
+        assert!(parse_owner("no-such-user-hopefully").is_err());
+    }
+
+    #[tokio::test]
+    async fn unix_sockets() {
+        let path = tempfile::tempdir().unwrap().into_path().join("socket");
+        let address = Address::Unix(path.clone());
+        let unix = UnixOptions {
+            mode: Some(0o600),
+            owner: None,
+        };
+
+        // Replaces a stale socket.
+        drop(Listener::bind(&address, &unix).await.unwrap());
+        let listener = Listener::bind(&address, &unix).await.unwrap();
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o7777,
+            0o600
+        );
+
+        let router = Router::new().route("/", get(|| async { "hello" }));
+        tokio::spawn(serve(listener, router, None, Shutdown::default()));
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("hello"));
+    }
+
+    #[tokio::test]
+    async fn tls() {
+        use std::sync::Arc;
+
+        use tokio_rustls::rustls::crypto::ring;
+        use tokio_rustls::rustls::pki_types::ServerName;
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+        use tokio_rustls::TlsConnector;
+
+        use crate::tls::Certificate;
+
+        let dir = tempfile::tempdir().unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        std::fs::write(&cert_path, include_bytes!("../tests/fixtures/tls-cert.pem")).unwrap();
+        std::fs::write(&key_path, include_bytes!("../tests/fixtures/tls-key.pem")).unwrap();
+        let certificate = Certificate::load(&cert_path, &key_path).unwrap();
+
+        let address = Address::Tcp("127.0.0.1:0".into());
+        let listener = Listener::bind(&address, &UnixOptions::default())
+            .await
+            .unwrap();
+        let port = match &listener {
+            Listener::Tcp(listener) => listener.local_addr().unwrap().port(),
+            Listener::Unix(..) => unreachable!(),
+        };
+
+        let router = Router::new().route("/", get(|| async { "hello" }));
+        let tls = Some(certificate.acceptor().unwrap());
+        tokio::spawn(serve(listener, router, tls, Shutdown::default()));
+
+        let mut roots = RootCertStore::empty();
+        let cert = std::fs::read(&cert_path).unwrap();
+        for cert in rustls_pemfile::certs(&mut &cert[..]) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let config = ClientConfig::builder_with_provider(ring::default_provider().into())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("hello"));
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown() {
+        use tokio::sync::oneshot;
+
+        let path = tempfile::tempdir().unwrap().into_path().join("socket");
+        let address = Address::Unix(path.clone());
+        let listener = Listener::bind(&address, &UnixOptions::default())
+            .await
+            .unwrap();
+
+        let (entered, handler_entered) = oneshot::channel();
+        let (release, released) = oneshot::channel::<()>();
+        let handler = std::sync::Arc::new(std::sync::Mutex::new(Some((entered, released))));
+        let router = Router::new().route(
+            "/",
+            get(move || {
+                let (entered, released) = handler.lock().unwrap().take().unwrap();
+                async move {
+                    entered.send(()).unwrap();
+                    released.await.unwrap();
+                    "done"
+                }
+            }),
+        );
+        let shutdown = Shutdown::default();
+        let server = tokio::spawn(serve(listener, router, None, shutdown.clone()));
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        handler_entered.await.unwrap();
+
+        // Stops accepting connections right away...
+        shutdown.start();
+        server.await.unwrap();
+        assert!(UnixStream::connect(&path).await.is_err());
+
+        // ...but lets the request in flight finish, before closing its connection.
+        release.send(()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("done"));
+
+        tokio::time::timeout(Duration::from_secs(5), shutdown.drained())
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+//! Advisory locking of the cache directory, so that only one server uses it at a time.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// The lock file, relative to `cache_dir`.
+const LOCK_FILE: &str = ".git-cache";
+
+/// How the cache directory is locked.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// For the server, or anything else that modifies the cache.
+    Exclusive,
+    /// For offline tooling that only reads the cache. Compatible with other shared locks, but not
+    /// with a running server.
+    Shared,
+}
+
+/// A `flock` on the cache directory, held until dropped.
+#[derive(Debug)]
+pub struct CacheLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl CacheLock {
+    /// Lock `cache_dir` without waiting, failing if it's already locked incompatibly.
+    ///
+    /// The exclusive holder writes its PID to the lock file, so that it can be named in the
+    /// errors of other processes.
+    pub fn acquire(cache_dir: &Path, mode: Mode) -> io::Result<Self> {
+        let path = cache_dir.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        let operation = match mode {
+            Mode::Exclusive => libc::LOCK_EX,
+            Mode::Shared => libc::LOCK_SH,
+        };
+
+        // SAFETY: `file` is a valid open file descriptor.
+        if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::WouldBlock {
+                return Err(err);
+            }
+            let holder = match holder(&mut file) {
+                Some(pid) => format!("process {pid}"),
+                None => "another process".to_string(),
+            };
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("cache directory {cache_dir:?} is locked by {holder}"),
+            ));
+        }
+
+        if mode == Mode::Exclusive {
+            file.set_len(0)?;
+            file.rewind()?;
+            writeln!(file, "{}", std::process::id())?;
+        }
+
+        Ok(Self { file, path })
+    }
+
+    /// Whether the lock is still effective, i.e. the lock file hasn't been deleted or replaced
+    /// (e.g. along with the cache directory).
+    pub fn is_held(&self) -> bool {
+        let (Ok(locked), Ok(current)) = (self.file.metadata(), std::fs::metadata(&self.path))
+        else {
+            return false;
+        };
+        (locked.dev(), locked.ino()) == (current.dev(), current.ino())
+    }
+}
+
+/// The PID recorded in the lock file, if that process is still running.
+///
+/// Shared holders don't record their PIDs, so this may also be a server that has since exited.
+fn holder(file: &mut File) -> Option<libc::pid_t> {
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).ok()?;
+    let pid = contents.trim().parse().ok()?;
+
+    // SAFETY: signal 0 only checks whether the process exists.
+    (unsafe { libc::kill(pid, 0) } == 0).then_some(pid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exclusive_and_shared() {
+        let cache_dir = tempfile::tempdir().unwrap().into_path();
+
+        let server = CacheLock::acquire(&cache_dir, Mode::Exclusive).unwrap();
+        let err = CacheLock::acquire(&cache_dir, Mode::Exclusive).unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with(&format!("locked by process {}", std::process::id())));
+        assert!(CacheLock::acquire(&cache_dir, Mode::Shared).is_err());
+        drop(server);
+
+        let tool = CacheLock::acquire(&cache_dir, Mode::Shared).unwrap();
+        let _other_tool = CacheLock::acquire(&cache_dir, Mode::Shared).unwrap();
+        assert!(CacheLock::acquire(&cache_dir, Mode::Exclusive).is_err());
+        drop(tool);
+    }
+
+    #[test]
+    fn deleted_lock_file() {
+        let cache_dir = tempfile::tempdir().unwrap().into_path();
+
+        let lock = CacheLock::acquire(&cache_dir, Mode::Exclusive).unwrap();
+        assert!(lock.is_held());
+
+        std::fs::remove_file(cache_dir.join(LOCK_FILE)).unwrap();
+        assert!(!lock.is_held());
+    }
+}
@@ -1,13 +1,13 @@
-use std::io::{self, Result};
+use std::io;
 
-use clap::Parser;
 use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::EnvFilter;
 
+use git_cache_http_server::cli;
 use git_cache_http_server::server::{start, Options};
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> anyhow::Result<()> {
     // Note that if `tracing_journald` is added, it will translate `Level::INFO` to syslog priority
     // `Notice`; priority `Informational` would require `Level::DEBUG`.
     tracing_subscriber::fmt::fmt()
@@ -20,7 +20,11 @@ async fn main() -> Result<()> {
         .compact()
         .init();
 
-    let options = Options::parse();
+    let options = Options::parse_with_config();
 
-    start(&options).await
+    match &options.command {
+        None => start(&options).await?,
+        Some(command) => cli::run(command, &options).await?,
+    }
+    Ok(())
 }
@@ -0,0 +1,182 @@
+//! Prometheus metrics, served at `/metrics`.
+
+use std::sync::LazyLock;
+
+use axum::extract::Request;
+use axum::http::Method;
+use axum::middleware::Next;
+use axum::response::Response;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+
+use crate::git;
+
+/// The media type of the metrics, in the OpenMetrics text format (which Prometheus understands).
+pub const MEDIA_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    requests: Family<RequestLabels, Counter>,
+    pub upstream_refs_duration: Histogram,
+    pub fetch_duration: Histogram,
+    pub upload_pack_bytes: Counter,
+    git_processes: Gauge,
+    repositories: Gauge,
+    disk_usage: Gauge,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RequestLabels {
+    route: &'static str,
+    status: u16,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let mut metrics = Self {
+            registry: Registry::with_prefix("git_cache"),
+            requests: Default::default(),
+            // From 10ms to about 40s.
+            upstream_refs_duration: Histogram::new(exponential_buckets(0.01, 2.0, 13)),
+            // From 100ms to about 30min.
+            fetch_duration: Histogram::new(exponential_buckets(0.1, 2.0, 15)),
+            upload_pack_bytes: Default::default(),
+            git_processes: Default::default(),
+            repositories: Default::default(),
+            disk_usage: Default::default(),
+        };
+
+        let registry = &mut metrics.registry;
+        registry.register(
+            "http_requests",
+            "HTTP requests, by route and status",
+            metrics.requests.clone(),
+        );
+        registry.register(
+            "upstream_refs_duration_seconds",
+            "Time taken by upstreams to advertise their refs (and authenticate clients)",
+            metrics.upstream_refs_duration.clone(),
+        );
+        registry.register(
+            "fetch_duration_seconds",
+            "Time taken to fetch from upstreams",
+            metrics.fetch_duration.clone(),
+        );
+        registry.register(
+            "upload_pack_bytes",
+            "Bytes streamed to clients by git-upload-pack",
+            metrics.upload_pack_bytes.clone(),
+        );
+        registry.register(
+            "git_processes",
+            "Git processes currently running",
+            metrics.git_processes.clone(),
+        );
+        registry.register(
+            "repositories",
+            "Repositories in the cache",
+            metrics.repositories.clone(),
+        );
+        registry.register(
+            "disk_usage_bytes",
+            "Disk space taken by the cached repositories, as of their last fetch or maintenance",
+            metrics.disk_usage.clone(),
+        );
+
+        metrics
+    }
+
+    /// Encode all metrics, along with the current number of `repositories` and their
+    /// `disk_usage`.
+    pub fn encode(&self, repositories: usize, disk_usage: u64) -> String {
+        self.git_processes.set(git::running_children() as i64);
+        self.repositories.set(repositories as i64);
+        self.disk_usage.set(disk_usage as i64);
+
+        let mut output = String::new();
+        prometheus_client::encoding::text::encode(&mut output, &self.registry)
+            .expect("writing to a string should not fail");
+        output
+    }
+}
+
+/// Count requests by route and response status.
+pub async fn count_requests(request: Request, next: Next) -> Response {
+    let route = route(
+        request.method(),
+        request.uri().path(),
+        request.uri().query(),
+    );
+    let response = next.run(request).await;
+    METRICS
+        .requests
+        .get_or_create(&RequestLabels {
+            route,
+            status: response.status().as_u16(),
+        })
+        .inc();
+    response
+}
+
+/// The route of a request, without the repository (to keep the number of labels bounded).
+fn route(method: &Method, path: &str, query: Option<&str>) -> &'static str {
+    match *method {
+        Method::GET if path == "/metrics" => "metrics",
+        Method::GET if path == "/healthz" => "healthz",
+        Method::GET if path == "/readyz" => "readyz",
+        _ if path.starts_with("/_admin/") => "admin",
+        Method::GET if path.ends_with("/info/refs") => match query {
+            Some("service=git-upload-pack") => "upload-pack-advertisement",
+            Some("service=git-receive-pack") => "receive-pack-advertisement",
+            _ => "other",
+        },
+        Method::GET if path.contains("/info/lfs/objects/") => "lfs-download",
+        Method::POST if path.ends_with("/git-upload-pack") => "upload-pack",
+        Method::POST if path.ends_with("/git-receive-pack") => "receive-pack",
+        Method::POST if path.ends_with("/info/lfs/objects/batch") => "lfs-batch",
+        _ => "other",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes() {
+        let get = |path, query| route(&Method::GET, path, query);
+        let post = |path| route(&Method::POST, path, None);
+
+        assert_eq!(get("/metrics", None), "metrics");
+        assert_eq!(get("/healthz", None), "healthz");
+        assert_eq!(get("/readyz", None), "readyz");
+        assert_eq!(get("/_admin/repos", None), "admin");
+        assert_eq!(
+            route(&Method::DELETE, "/_admin/repos/example.com/a", None),
+            "admin"
+        );
+        assert_eq!(
+            get("/example.com/a/info/refs", Some("service=git-upload-pack")),
+            "upload-pack-advertisement"
+        );
+        assert_eq!(
+            get("/example.com/a/info/refs", Some("service=git-receive-pack")),
+            "receive-pack-advertisement"
+        );
+        assert_eq!(get("/example.com/a/info/refs", None), "other");
+        assert_eq!(
+            get("/example.com/a/info/lfs/objects/1234", None),
+            "lfs-download"
+        );
+        assert_eq!(post("/example.com/a/git-upload-pack"), "upload-pack");
+        assert_eq!(post("/example.com/a/git-receive-pack"), "receive-pack");
+        assert_eq!(post("/example.com/a/info/lfs/objects/batch"), "lfs-batch");
+        assert_eq!(post("/metrics"), "other");
+    }
+}
@@ -0,0 +1,207 @@
+//! Mirroring: fetching some repositories periodically in the background, so that ref discovery
+//! usually finds them already up to date.
+//!
+//! Each mirror is either an upstream URL, which is also cloned if it isn't cached yet, or a glob
+//! pattern over the repositories already cached, named as in clone URLs (e.g. `github.com/org/*`).
+//! Fetches use the server's own credentials (if any), through the same path as client requests.
+//!
+//! Repositories mirrored by upstream URL are pinned, since evicting them would only get them
+//! cloned again on the next round.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::http::Uri;
+use tokio::time::MissedTickBehavior;
+
+use crate::repo::Index;
+
+/// Repositories to fetch every `interval`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mirror {
+    repos: Repos,
+    interval: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Repos {
+    Upstream(Uri),
+    /// `*` matches within a path component, `**` across them, and `?` matches any one character
+    /// other than `/`.
+    Pattern(String),
+}
+
+impl Mirror {
+    pub fn new(repos: &str, interval: Duration) -> Result<Self, String> {
+        if interval.is_zero() {
+            return Err(String::from("mirroring interval must not be zero"));
+        }
+
+        let repos = if repos.contains("://") {
+            let upstream: Uri = repos
+                .parse()
+                .map_err(|err| format!("invalid upstream URL {repos:?}: {err}"))?;
+            if !matches!(upstream.scheme_str(), Some("http" | "https")) || upstream.host().is_none()
+            {
+                return Err(format!("unsupported upstream URL {repos:?}"));
+            }
+            Repos::Upstream(upstream)
+        } else {
+            let pattern = repos.trim_matches('/');
+            if pattern.is_empty() {
+                return Err(String::from("empty repository pattern"));
+            }
+            Repos::Pattern(pattern.strip_suffix(".git").unwrap_or(pattern).to_string())
+        };
+
+        Ok(Self { repos, interval })
+    }
+
+    /// The repository to pin, as it appears in clone URLs, if mirrored by upstream URL.
+    pub fn pinned(&self) -> Option<PathBuf> {
+        match &self.repos {
+            Repos::Upstream(upstream) => {
+                let host = upstream
+                    .host()
+                    .expect("upstream should have been validated");
+                Some(Path::new(host).join(upstream.path().trim_start_matches('/')))
+            }
+            Repos::Pattern(_) => None,
+        }
+    }
+
+    /// Fetch the repositories right away, and then every `interval`, forever.
+    ///
+    /// Mirroring doesn't count as accessing the repositories, so it doesn't keep those matched by
+    /// a pattern from being evicted.
+    pub async fn run(self, index: Arc<Index>) {
+        let mut interval = tokio::time::interval(self.interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            for upstream in self.upstreams(&index).await {
+                tracing::debug!(%upstream, "mirroring repository");
+                if let Err(err) = index.warm(upstream.clone()).await {
+                    tracing::warn!(%upstream, error = ?err, "failed to mirror repository");
+                }
+            }
+        }
+    }
+
+    async fn upstreams(&self, index: &Index) -> Vec<Uri> {
+        match &self.repos {
+            Repos::Upstream(upstream) => vec![upstream.clone()],
+            Repos::Pattern(pattern) => {
+                let pattern: Vec<char> = pattern.chars().collect();
+                let list = index.list().await;
+                list.into_iter()
+                    .filter(|status| {
+                        let name = status.name.with_extension("");
+                        let name: Vec<char> = name.to_string_lossy().chars().collect();
+                        glob_match(&pattern, &name)
+                    })
+                    .map(|status| status.upstream)
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Parse `REPO=DURATION`.
+impl FromStr for Mirror {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (repos, interval) = s.rsplit_once('=').ok_or("expected REPO=DURATION")?;
+        let interval = humantime::parse_duration(interval).map_err(|err| err.to_string())?;
+        Self::new(repos, interval)
+    }
+}
+
+fn glob_match(pattern: &[char], name: &[char]) -> bool {
+    match pattern {
+        [] => name.is_empty(),
+        ['*', '*', rest @ ..] => (0..=name.len()).any(|i| glob_match(rest, &name[i..])),
+        ['*', rest @ ..] => (0..=name.len())
+            .take_while(|&i| i == 0 || name[i - 1] != '/')
+            .any(|i| glob_match(rest, &name[i..])),
+        ['?', rest @ ..] => matches!(name, [c, tail @ ..] if *c != '/' && glob_match(rest, tail)),
+        [p, rest @ ..] => matches!(name, [c, tail @ ..] if c == p && glob_match(rest, tail)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::git::MockGit as Git;
+
+    #[test]
+    fn parsing() {
+        let mirror: Mirror = "https://example.com/a/b?c=d=5m".parse().unwrap();
+        assert_eq!(
+            mirror.repos,
+            Repos::Upstream("https://example.com/a/b?c=d".parse().unwrap())
+        );
+        assert_eq!(mirror.interval, Duration::from_secs(300));
+
+        assert_eq!(mirror.pinned(), Some(PathBuf::from("example.com/a/b")));
+
+        let mirror: Mirror = "example.com/a/*.git=1h".parse().unwrap();
+        assert_eq!(mirror.repos, Repos::Pattern("example.com/a/*".into()));
+        assert_eq!(mirror.pinned(), None);
+
+        assert!("example.com/a".parse::<Mirror>().is_err());
+        assert!("example.com/a=0s".parse::<Mirror>().is_err());
+        assert!("ssh://example.com/a=5m".parse::<Mirror>().is_err());
+        assert!("/=5m".parse::<Mirror>().is_err());
+    }
+
+    #[test]
+    fn globs() {
+        let matches = |pattern: &str, name: &str| {
+            let pattern: Vec<char> = pattern.chars().collect();
+            let name: Vec<char> = name.chars().collect();
+            glob_match(&pattern, &name)
+        };
+
+        assert!(matches("example.com/a", "example.com/a"));
+        assert!(!matches("example.com/a", "example.com/ab"));
+        assert!(matches("example.com/*", "example.com/a"));
+        assert!(!matches("example.com/*", "example.com/a/b"));
+        assert!(matches("example.com/**", "example.com/a/b"));
+        assert!(matches("*/a/*", "example.com/a/b"));
+        assert!(matches("example.com/a?", "example.com/ab"));
+        assert!(!matches("example.com?a", "example.com/a"));
+        assert!(matches("**/linux", "example.com/torvalds/linux"));
+    }
+
+    #[tokio::test]
+    async fn pattern_over_cached_repos() {
+        let cache_dir = tempdir().unwrap().into_path();
+
+        let mut mock_git = Git::default();
+        mock_git.expect_init().times(3).returning(|_| Ok(()));
+
+        let index = Index::new(cache_dir, mock_git, Default::default());
+        for upstream in [
+            "https://example.com/a/b",
+            "https://example.com/a/c.git",
+            "https://example.com/d/e",
+        ] {
+            index.open(upstream.parse().unwrap()).await.unwrap();
+        }
+
+        let mirror: Mirror = "example.com/a/*=1m".parse().unwrap();
+        assert_eq!(
+            mirror.upstreams(&index).await,
+            ["https://example.com/a/b", "https://example.com/a/c.git"]
+        );
+
+        let mirror: Mirror = "https://example.com/f/g=1m".parse().unwrap();
+        assert_eq!(mirror.upstreams(&index).await, ["https://example.com/f/g"]);
+    }
+}
@@ -0,0 +1,95 @@
+//! Object pools, to store the objects shared by forks only once.
+//!
+//! A pool is a bare repository under `cache_dir/.pools`. It fetches the refs of each of its
+//! members into `refs/forks/<member id>/`, and members borrow its objects through their
+//! `objects/info/alternates`. Objects are only dropped from members once they are repacked with
+//! `--local`, after the pool itself has been repacked.
+
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+/// Where pools are kept, relative to `cache_dir`.
+pub const POOLS_DIR: &str = ".pools";
+
+/// Whether `name` can be used for a pool: no path separators, and not hidden (which also rules out
+/// `.` and `..`).
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+/// The name of the pool for forks detected from their shared `root` commit.
+pub fn root_commit_name(root: &str) -> String {
+    format!("root-{root}")
+}
+
+pub fn path(cache_dir: &Path, name: &str) -> PathBuf {
+    cache_dir.join(POOLS_DIR).join(format!("{name}.git"))
+}
+
+/// How the refs of `repo` (relative to `cache_dir`) are namespaced in its pool.
+pub fn member_id(repo: &Path) -> String {
+    let digest = Sha256::digest(repo.as_os_str().as_bytes());
+    format!("{:x}", digest)[..32].to_string()
+}
+
+/// The `objects/info/alternates` entry that links `repo` (relative to `cache_dir`) to pool `name`.
+///
+/// It's relative to the objects directory of `repo`, so that `cache_dir` can be moved around.
+pub fn alternates(repo: &Path, name: &str) -> PathBuf {
+    let mut alternates: PathBuf = repo.components().map(|_| "..").collect();
+    alternates.push("..");
+    alternates
+        .join(POOLS_DIR)
+        .join(format!("{name}.git"))
+        .join("objects")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert!(is_valid_name("linux"));
+        assert!(is_valid_name("rust-lang_1.0"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name(".."));
+        assert!(!is_valid_name(".hidden"));
+        assert!(!is_valid_name("a/b"));
+    }
+
+    #[test]
+    fn alternates_are_relative() {
+        let cache_dir = Path::new("/cache");
+        let repo = Path::new("example.com/a/b.git");
+
+        assert_eq!(
+            alternates(repo, "mock"),
+            Path::new("../../../../.pools/mock.git/objects")
+        );
+
+        // Resolved from the objects directory of the repository.
+        let resolved = cache_dir
+            .join(repo)
+            .join("objects")
+            .join(alternates(repo, "mock"));
+        let resolved: PathBuf = resolved
+            .components()
+            .fold(PathBuf::new(), |mut resolved, comp| {
+                match comp {
+                    std::path::Component::ParentDir => {
+                        resolved.pop();
+                    }
+                    comp => resolved.push(comp),
+                }
+                resolved
+            });
+        assert_eq!(resolved, path(cache_dir, "mock").join("objects"));
+    }
+}
@@ -1,41 +1,281 @@
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Component, Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex as SyncMutex};
+use std::time::{Duration, Instant, SystemTime};
 
-use anyhow::Context;
+use anyhow::{anyhow, Context};
+use axum::http::HeaderValue;
 use axum::http::Uri;
-use axum::{body::Bytes, http::HeaderValue};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tokio::fs;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify, OwnedRwLockReadGuard, RwLock};
+use tokio::time::MissedTickBehavior;
+use uuid::Uuid;
 
 use crate::error::{Error, Result};
 
+use crate::git::{GitAsyncRead, Refs};
+use crate::metrics::METRICS;
+use crate::pool;
+
 #[cfg(not(test))]
-use crate::git::{Git, GitAsyncRead};
+use crate::git::Git;
 #[cfg(test)]
-use crate::git::{GitAsyncRead, MockGit as Git};
+use crate::git::MockGit as Git;
+
+/// How repositories are kept up to date.
+#[derive(Debug, Default)]
+pub struct Config {
+    /// How long after being fetched a repository can be served without updating it first.
+    pub fresh_for: Duration,
+    /// Overrides of `fresh_for` for specific upstream hosts.
+    pub host_fresh_for: HashMap<String, Duration>,
+    /// If set, serve the local copy when the upstream is unavailable, but only to clients the
+    /// upstream authorized within this long.
+    pub serve_stale_within: Option<Duration>,
+    /// If set, evict the least recently used repositories once they take more disk space.
+    pub disk_quota: Option<u64>,
+    /// Repositories, relative to `cache_dir`, that are never evicted.
+    pub pinned: Vec<PathBuf>,
+    /// Run maintenance on repositories fetched since, once this long after the last time.
+    pub maintenance_interval: Option<Duration>,
+    /// Run maintenance on repositories after this many fetches.
+    pub maintenance_after_fetches: Option<u32>,
+    /// Repositories, relative to `cache_dir`, that share objects with the other forks in the same
+    /// named pool.
+    pub fork_groups: HashMap<PathBuf, String>,
+    /// Share objects between repositories that have the same root commit.
+    pub detect_forks: bool,
+}
+
+/// Where removed repositories are moved until they're deleted, relative to `cache_dir`.
+const TRASH_DIR: &str = ".trash";
+
+/// The least time between saving accesses to repositories that aren't fetched, with a longer
+/// `fresh_for` taking precedence.
+const ACCESS_SAVE_PERIOD: Duration = Duration::from_secs(60);
+
+/// How often to check which repositories are due for maintenance.
+const MAINTENANCE_CHECK_PERIOD: Duration = Duration::from_secs(60);
+
+/// How a repository is used, to decide what to evict.
+#[derive(Debug)]
+pub struct Usage {
+    pub last_access: SystemTime,
+    /// The `last_access` last persisted in the metadata.
+    pub saved_access: SystemTime,
+    pub last_fetch: Option<SystemTime>,
+    /// Disk usage, as of the last fetch (only measured with a disk quota) or maintenance.
+    pub size: u64,
+    /// Pinned with `--pin`, or mirrored by upstream URL.
+    pub pinned: bool,
+    /// Pinned through the admin API, which is persisted in the metadata.
+    pub pinned_by_admin: bool,
+    /// The object pool it's a member of, if any.
+    pub pool: Option<String>,
+}
+
+impl Usage {
+    pub fn is_pinned(&self) -> bool {
+        self.pinned || self.pinned_by_admin
+    }
+}
+
+/// A snapshot of a cached repository, taken without waiting for it to be idle.
+#[derive(Debug)]
+pub struct Status {
+    /// The path of the local copy relative to `cache_dir`.
+    pub name: PathBuf,
+    pub upstream: Uri,
+    pub last_access: SystemTime,
+    pub last_fetch: Option<SystemTime>,
+    /// Disk usage, as of the last fetch or maintenance.
+    pub size: u64,
+    pub pinned: bool,
+}
+
+/// What else is known about a repository, while it isn't being fetched.
+#[derive(Debug)]
+pub struct Details {
+    pub local: PathBuf,
+    /// The ref HEAD points to, as last advertised by the upstream.
+    pub head: Option<String>,
+    /// The object pool shared with forks, if any.
+    pub pool: Option<String>,
+    pub fresh: bool,
+}
+
+/// How a repository shares objects with its forks.
+#[derive(Clone, Debug, PartialEq)]
+enum Sharing {
+    Off,
+    /// Join the configured pool after the first fetch.
+    Group(String),
+    /// Join the pool for its root commit after the first fetch.
+    RootCommit,
+    /// Member of the named pool.
+    Pool(String),
+}
+
+#[derive(Debug)]
+struct Cached {
+    repo: Arc<RwLock<Repo>>,
+    /// Also kept outside of `repo`, for the same reason as `usage`.
+    upstream: Uri,
+    /// Also kept outside of `repo`, so that it can be checked without waiting for any locks.
+    usage: Arc<SyncMutex<Usage>>,
+}
 
 #[derive(Debug)]
 pub struct Index {
     git: Arc<Git>,
-    index: Arc<Mutex<HashMap<PathBuf, Arc<Mutex<Repo>>>>>,
+    index: Arc<Mutex<HashMap<PathBuf, Cached>>>,
     cache_dir: PathBuf,
+    config: Config,
+    fetched: Arc<Notify>,
+    /// Held while joining or deleting object pools, so that a pool isn't deleted as it's joined.
+    pools: Arc<Mutex<()>>,
 }
 
 impl Index {
-    pub fn new(cache_dir: PathBuf, git: Git) -> Self {
+    pub fn new(cache_dir: PathBuf, git: Git, config: Config) -> Self {
         Self {
             git: Arc::new(git),
             index: Default::default(),
             cache_dir,
+            config,
+            fetched: Default::default(),
+            pools: Default::default(),
         }
     }
 
-    pub async fn open(&self, upstream: Uri) -> Result<Arc<Mutex<Repo>>> {
-        let host = Path::new(upstream.host().ok_or(Error::NotFound)?);
+    pub fn git(&self) -> &Git {
+        &self.git
+    }
+
+    /// The number of cached repositories, and their total disk usage as of their last fetch or
+    /// maintenance.
+    pub async fn usage(&self) -> (usize, u64) {
+        let index = self.index.lock().await;
+        let size = index
+            .values()
+            .map(|cached| cached.usage.lock().unwrap().size)
+            .sum();
+        (index.len(), size)
+    }
+
+    /// Get a repository if it's already cached, without counting it as an access.
+    pub async fn get(&self, upstream: &Uri) -> Result<Option<Arc<RwLock<Repo>>>> {
+        let local = self.local_path(upstream)?;
+        let index = self.index.lock().await;
+        Ok(index.get(&local).map(|cached| cached.repo.clone()))
+    }
+
+    /// Get a repository, initializing it if necessary.
+    ///
+    /// Serving the local copy only requires a read lock, so that it can be done concurrently, but
+    /// updating it requires exclusive access (a write lock).
+    pub async fn open(&self, upstream: Uri) -> Result<Arc<RwLock<Repo>>> {
+        let local = self.local_path(&upstream)?;
+
+        let mut index = self.index.lock().await;
+
+        match index.entry(local.clone()) {
+            Entry::Occupied(e) => {
+                let now = SystemTime::now();
+                let save = {
+                    let mut usage = e.get().usage.lock().unwrap();
+                    usage.last_access = now;
+                    // Fetches persist it too, but fresh repositories can go a long time without.
+                    let period = self.config.fresh_for.max(ACCESS_SAVE_PERIOD);
+                    now.duration_since(usage.saved_access)
+                        .is_ok_and(|since| since >= period)
+                };
+                let repo = e.get().repo.clone();
+                drop(index);
+
+                // Being fetched means it's about to be saved anyway.
+                if let (true, Ok(guard)) = (save, repo.try_read()) {
+                    if let Err(err) = guard.save_metadata().await {
+                        tracing::warn!(local = ?guard.local, error = ?err, "failed to save access");
+                    }
+                }
+                Ok(repo)
+            }
+            Entry::Vacant(e) => {
+                fs::create_dir_all(&local)
+                    .await
+                    .context("failed to create directory for repository")?;
+
+                self.git.init(local.clone()).await?;
+
+                let cached = self.cached(upstream, local, None).await?;
+                cached.repo.read().await.save_metadata().await?;
+
+                let repo = cached.repo.clone();
+                e.insert(cached);
+                Ok(repo)
+            }
+        }
+    }
+
+    /// Add the repositories already in `cache_dir` to the index, from their metadata files.
+    ///
+    /// Repositories without (valid) metadata are skipped, and so are never served or evicted.
+    pub async fn load(&self) -> Result<()> {
+        let cache_dir = self.cache_dir.clone();
+        let found = tokio::task::spawn_blocking(move || find_repos(&cache_dir))
+            .await
+            .expect("repository scan task should not panic")
+            .context("failed to scan cache directory")?;
+
+        let mut index = self.index.lock().await;
+
+        for local in found {
+            let metadata = match Metadata::read(&local).await {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    tracing::warn!(?local, error = ?err, "skipping repository with invalid metadata");
+                    continue;
+                }
+            };
+
+            let upstream: Uri = match metadata.upstream.parse() {
+                Ok(upstream) => upstream,
+                Err(err) => {
+                    tracing::warn!(?local, error = ?err, "skipping repository with invalid upstream");
+                    continue;
+                }
+            };
+            if self.local_path(&upstream).ok().as_ref() != Some(&local) {
+                tracing::warn!(?local, %upstream, "skipping repository not where its upstream maps to");
+                continue;
+            }
+
+            let cached = self.cached(upstream, local.clone(), Some(metadata)).await?;
+            index.insert(local, cached);
+        }
+
+        tracing::info!(repos = index.len(), "loaded cached repositories");
+        Ok(())
+    }
+
+    /// Where the local copy of `upstream` is kept.
+    fn local_path(&self, upstream: &Uri) -> Result<PathBuf> {
+        let host = upstream.host().ok_or(Error::NotFound)?;
         let path = Path::new(&upstream.path()[1..]);
 
+        // Dot-prefixed names at the top of `cache_dir` are reserved for our own use.
+        if host.starts_with('.') {
+            tracing::warn!(?host, "disallowed host");
+            return Err(Error::NotFound);
+        }
+        let host = Path::new(host);
+
         // Guard against path traversal attacks, as well as any other "strange" path components
         // that may cause issues.
         let mut local = self.cache_dir.clone();
@@ -52,29 +292,653 @@ impl Index {
         }
         local.set_extension("git");
 
+        Ok(local)
+    }
+
+    /// Set up an index entry for an initialized repository, restoring its state from `metadata`
+    /// if available.
+    async fn cached(
+        &self,
+        upstream: Uri,
+        local: PathBuf,
+        metadata: Option<Metadata>,
+    ) -> Result<Cached> {
+        let host = upstream
+            .host()
+            .expect("upstream should have been validated");
+        let fresh_for = self.config.host_fresh_for.get(host).copied();
+        let fresh_for = fresh_for.unwrap_or(self.config.fresh_for);
+
+        let name = local
+            .strip_prefix(&self.cache_dir)
+            .expect("repository should be in cache_dir")
+            .to_path_buf();
+
+        let pool = metadata.as_ref().and_then(|metadata| metadata.pool.clone());
+        let sharing = match (pool.clone(), self.config.fork_groups.get(&name)) {
+            (Some(pool), _) => Sharing::Pool(pool),
+            (None, Some(group)) => Sharing::Group(group.clone()),
+            (None, None) if self.config.detect_forks => Sharing::RootCommit,
+            (None, None) => Sharing::Off,
+        };
+
+        let last_access = match &metadata {
+            Some(metadata) => metadata.last_access,
+            None => SystemTime::now(),
+        };
+        let usage = Arc::new(SyncMutex::new(Usage {
+            last_access,
+            saved_access: last_access,
+            last_fetch: metadata.as_ref().and_then(|metadata| metadata.last_fetch),
+            size: match &metadata {
+                Some(metadata) => metadata.size,
+                None => disk_usage(local.clone()).await?,
+            },
+            pinned: self
+                .config
+                .pinned
+                .iter()
+                .any(|pinned| self.cache_dir.join(pinned).with_extension("git") == local),
+            pinned_by_admin: metadata.as_ref().is_some_and(|metadata| metadata.pinned),
+            pool,
+        }));
+
+        let repo = Arc::new(RwLock::new(Repo {
+            git: self.git.clone(),
+            upstream: upstream.clone(),
+            local,
+            name,
+            cache_dir: self.cache_dir.clone(),
+            sharing,
+            head: metadata.as_ref().and_then(|metadata| metadata.head.clone()),
+            fresh_for,
+            serve_stale_within: self.config.serve_stale_within,
+            disk_quota: self.config.disk_quota,
+            last_fetch: metadata
+                .as_ref()
+                .and_then(|metadata| metadata.last_fetch)
+                .and_then(instant_from),
+            last_fetch_finished: None,
+            authorized: Default::default(),
+            usage: usage.clone(),
+            fetched: self.fetched.clone(),
+            pools: self.pools.clone(),
+            maintenance: SyncMutex::new(Maintenance {
+                last: Instant::now(),
+                fetches: 0,
+            }),
+        }));
+
+        Ok(Cached {
+            repo,
+            upstream,
+            usage,
+        })
+    }
+
+    /// Keep the cache within its disk quota, checking right away and then after every fetch.
+    pub async fn evict_after_fetches(&self) {
+        loop {
+            if let Err(err) = self.evict().await {
+                tracing::error!(error = ?err, "failed to evict repositories");
+            }
+            self.fetched.notified().await;
+        }
+    }
+
+    /// Run maintenance on repositories as it becomes due, forever.
+    pub async fn maintain_periodically(&self) {
+        let mut interval = tokio::time::interval(MAINTENANCE_CHECK_PERIOD);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            self.maintain_due().await;
+        }
+    }
+
+    /// Run maintenance on the repositories that are due for it, one at a time.
+    ///
+    /// Object pools are maintained before any of their members that are due.
+    pub async fn maintain_due(&self) {
+        self.maintain_where(|repo| repo.maintenance_due(&self.config))
+            .await
+    }
+
+    /// Run maintenance on all repositories and object pools, regardless of whether it's due.
+    pub async fn maintain_all(&self) {
+        self.maintain_where(|_| true).await
+    }
+
+    async fn maintain_where(&self, due: impl Fn(&Repo) -> bool) {
+        let repos: Vec<_> = {
+            let index = self.index.lock().await;
+            index.values().map(|cached| cached.repo.clone()).collect()
+        };
+
+        let mut pools: BTreeMap<String, Vec<Arc<RwLock<Repo>>>> = BTreeMap::new();
+
+        for repo in repos {
+            // Exclude fetches, but keep serving clients in the meantime.
+            let guard = repo.read().await;
+            if let Sharing::Pool(pool) = &guard.sharing {
+                let pool = pool.clone();
+                drop(guard);
+                pools.entry(pool).or_default().push(repo);
+                continue;
+            }
+            if !due(&guard) {
+                continue;
+            }
+            if let Err(err) = guard.maintain().await {
+                tracing::error!(local = ?guard.local, error = ?err, "failed to maintain repository");
+            }
+        }
+
+        // Only lock the members of one pool at a time.
+        for (pool, members) in pools {
+            let mut guards = Vec::with_capacity(members.len());
+            for member in members {
+                guards.push(member.read_owned().await);
+            }
+            if !guards.iter().any(|member| due(member)) {
+                continue;
+            }
+            if let Err(err) = self.maintain_pool(&pool, &guards, &due).await {
+                tracing::error!(pool, error = ?err, "failed to maintain object pool");
+            }
+        }
+    }
+
+    /// Maintain an object pool, and then its members that are due.
+    ///
+    /// The refs of all members are synced into the pool first, so that it keeps every object they
+    /// may still borrow. Callers must hold read locks on all `members`, to exclude fetches until
+    /// the members are done too.
+    async fn maintain_pool(
+        &self,
+        pool: &str,
+        members: &[OwnedRwLockReadGuard<Repo>],
+        due: impl Fn(&Repo) -> bool,
+    ) -> Result<()> {
+        let path = pool::path(&self.cache_dir, pool);
+
+        for member in members {
+            self.git
+                .sync_pool(
+                    path.clone(),
+                    member.local.clone(),
+                    pool::member_id(&member.name),
+                )
+                .await?;
+        }
+
+        tracing::info!(pool, "running maintenance on object pool");
+        self.git.maintain(path).await?;
+
+        for member in members {
+            if !due(member) {
+                continue;
+            }
+            if let Err(err) = member.maintain().await {
+                tracing::error!(local = ?member.local, error = ?err, "failed to maintain repository");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Evict the least recently used repositories, from disk and from the index, until the cache
+    /// fits within its disk quota.
+    ///
+    /// Pinned repositories are never evicted, and neither are the ones currently in use. Object
+    /// pools count towards the quota too, and are deleted along with their last member.
+    pub async fn evict(&self) -> Result<()> {
+        let Some(quota) = self.config.disk_quota else {
+            return Ok(());
+        };
+
+        let pool_sizes = self.pool_sizes().await?;
+
         let mut index = self.index.lock().await;
 
-        match index.entry(local.clone()) {
-            Entry::Occupied(e) => Ok(e.get().clone()),
-            Entry::Vacant(e) => {
-                fs::create_dir_all(&local)
-                    .await
-                    .context("failed to create directory for repository")?;
+        let mut total: u64 = index
+            .values()
+            .map(|cached| cached.usage.lock().unwrap().size)
+            .sum::<u64>()
+            + pool_sizes.values().sum::<u64>();
+        if total <= quota {
+            return Ok(());
+        }
 
-                self.git.init(local.clone()).await?;
+        let mut members: HashMap<String, usize> = HashMap::new();
+        for cached in index.values() {
+            if let Some(pool) = &cached.usage.lock().unwrap().pool {
+                *members.entry(pool.clone()).or_default() += 1;
+            }
+        }
 
-                let repo = Arc::new(Mutex::new(Repo {
-                    git: self.git.clone(),
-                    upstream: upstream.clone(),
-                    local,
-                }));
+        // With the index locked, the only other references to idle repositories are in the index
+        // itself.
+        let mut candidates: Vec<_> = index
+            .iter()
+            .filter(|(_, cached)| Arc::strong_count(&cached.repo) == 1)
+            .filter_map(|(local, cached)| {
+                let usage = cached.usage.lock().unwrap();
+                (!usage.is_pinned()).then(|| {
+                    let pool = usage.pool.clone();
+                    (usage.last_access, usage.size, local.clone(), pool)
+                })
+            })
+            .collect();
+        candidates.sort();
 
-                e.insert(repo.clone());
+        let mut evicted = vec![];
+        for (_, size, local, pool) in candidates {
+            if total <= quota {
+                break;
+            }
 
-                Ok(repo)
+            evicted.push(self.remove(&mut index, &local).await?);
+            tracing::info!(?local, size, "evicted repository");
+            total = total.saturating_sub(size);
+
+            if let Some(pool) = pool {
+                let count = members.entry(pool.clone()).or_default();
+                *count = count.saturating_sub(1);
+                if *count == 0 {
+                    let size = pool_sizes.get(&pool).copied().unwrap_or(0);
+                    total = total.saturating_sub(size);
+                }
             }
         }
+
+        drop(index);
+
+        if total > quota {
+            tracing::warn!(
+                total,
+                quota,
+                "cache over disk quota, but nothing else can be evicted"
+            );
+        }
+
+        for removed in evicted {
+            self.delete(removed).await?;
+        }
+
+        Ok(())
     }
+
+    /// The disk usage of each object pool, by name.
+    async fn pool_sizes(&self) -> Result<HashMap<String, u64>> {
+        let mut sizes = HashMap::new();
+
+        let mut entries = match fs::read_dir(self.cache_dir.join(pool::POOLS_DIR)).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(sizes),
+            Err(err) => return Err(anyhow!(err).context("failed to list object pools").into()),
+        };
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .context("failed to list object pools")?
+        {
+            let file_name = entry.file_name();
+            let Some(name) = file_name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".git"))
+            else {
+                continue;
+            };
+            sizes.insert(name.to_string(), disk_usage(entry.path()).await?);
+        }
+
+        Ok(sizes)
+    }
+
+    /// Move an idle repository to the trash and remove it from the (locked) `index`.
+    ///
+    /// Only moving it out of the way while the index is locked lets the repository be created again
+    /// right away, while it's `delete`d later.
+    async fn remove(&self, index: &mut HashMap<PathBuf, Cached>, local: &Path) -> Result<Removed> {
+        let trash = self.cache_dir.join(TRASH_DIR);
+        fs::create_dir_all(&trash)
+            .await
+            .context("failed to create trash directory")?;
+
+        let target = trash.join(Uuid::new_v4().to_string());
+        fs::rename(local, &target)
+            .await
+            .context("failed to move repository to trash")?;
+        let cached = index
+            .remove(local)
+            .expect("removed repository should be in the index");
+
+        let repo = cached
+            .repo
+            .try_read()
+            .expect("idle repository should not be locked");
+        let pool = match &repo.sharing {
+            Sharing::Pool(pool) => Some((pool.clone(), pool::member_id(&repo.name))),
+            _ => None,
+        };
+
+        Ok(Removed { target, pool })
+    }
+
+    /// Delete a repository moved to the trash by `remove`.
+    async fn delete(&self, removed: Removed) -> Result<()> {
+        fs::remove_dir_all(&removed.target)
+            .await
+            .context("failed to delete removed repository")?;
+
+        let Some((pool, member)) = removed.pool else {
+            return Ok(());
+        };
+        let path = pool::path(&self.cache_dir, &pool);
+
+        let _pools = self.pools.lock().await;
+        let in_use = {
+            let index = self.index.lock().await;
+            index
+                .values()
+                .any(|cached| cached.usage.lock().unwrap().pool.as_ref() == Some(&pool))
+        };
+
+        if in_use {
+            // Its objects will be dropped from the pool the next time it's maintained.
+            if let Err(err) = self.git.leave_pool(path, member).await {
+                tracing::warn!(pool, error = ?err, "failed to remove deleted repository from pool");
+            }
+        } else {
+            match fs::remove_dir_all(&path).await {
+                // Already deleted along with another member.
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => tracing::warn!(pool, error = ?err, "failed to delete object pool"),
+                Ok(()) => tracing::info!(pool, "deleted object pool without members"),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The status of every cached repository, by name.
+    pub async fn list(&self) -> Vec<Status> {
+        let index = self.index.lock().await;
+        let mut list: Vec<_> = index
+            .iter()
+            .map(|(local, cached)| self.status(local, cached))
+            .collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+
+    /// The status of the repository `name`, as it appears in clone URLs, and its details unless
+    /// it's being fetched.
+    pub async fn details(&self, name: &Path) -> Result<(Status, Option<Details>)> {
+        let local = self.named(name);
+        let index = self.index.lock().await;
+        let cached = index.get(&local).ok_or(Error::NotFound)?;
+        let status = self.status(&local, cached);
+        let details = cached.repo.try_read().ok().map(|repo| Details {
+            local: repo.local.clone(),
+            head: repo.head.clone(),
+            pool: match &repo.sharing {
+                Sharing::Pool(pool) => Some(pool.clone()),
+                _ => None,
+            },
+            fresh: repo.is_fresh(),
+        });
+        Ok((status, details))
+    }
+
+    /// Clone or update `upstream` now, with the server's own credentials (if any).
+    ///
+    /// Like [`Index::refresh`], this fetches directly: checking the upstream refs first would take
+    /// an unauthenticated request, which private upstreams refuse. This doesn't count as an access
+    /// to repositories already cached.
+    pub async fn warm(&self, upstream: Uri) -> Result<()> {
+        let repo = match self.get(&upstream).await? {
+            Some(repo) => repo,
+            None => self.open(upstream).await?,
+        };
+
+        // Any fetch that finishes while we wait for the lock is just as good.
+        let requested = Instant::now();
+        let (result, local) = {
+            let mut repo = repo.write().await;
+            let result = repo.fetch(None, None, Some(requested)).await;
+            match result {
+                // Don't leave an empty repository behind, e.g. for a mistyped URL.
+                Err(_) if repo.last_fetch.is_none() => (result, Some(repo.local.clone())),
+                _ => (result, None),
+            }
+        };
+        drop(repo);
+
+        if let Some(local) = local {
+            let mut index = self.index.lock().await;
+            // Unless a client started using it in the meantime.
+            let idle = index
+                .get(&local)
+                .is_some_and(|cached| Arc::strong_count(&cached.repo) == 1);
+            if idle {
+                let removed = self.remove(&mut index, &local).await?;
+                drop(index);
+                tracing::info!(?local, "removed repository that failed to warm");
+                self.delete(removed).await?;
+            }
+        }
+
+        result
+    }
+
+    /// Delete what was left in the trash, e.g. by an interrupted eviction.
+    pub async fn empty_trash(&self) -> Result<()> {
+        match fs::remove_dir_all(self.cache_dir.join(TRASH_DIR)).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(anyhow::Error::new(err)
+                .context("failed to empty trash")
+                .into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fetch the repository `name` from its upstream now, with the server's own credentials (if
+    /// any), regardless of whether it's still fresh.
+    pub async fn refresh(&self, name: &Path) -> Result<()> {
+        let repo = {
+            let index = self.index.lock().await;
+            let cached = index.get(&self.named(name)).ok_or(Error::NotFound)?;
+            cached.repo.clone()
+        };
+
+        // Any fetch that finishes while we wait for the lock is just as good.
+        let requested = Instant::now();
+        let mut repo = repo.write().await;
+        tracing::info!(upstream = %repo.upstream, "refreshing repository");
+        repo.fetch(None, None, Some(requested)).await
+    }
+
+    /// Delete the repository `name` from disk and from the index, even if pinned.
+    ///
+    /// Repositories that are in use (e.g. being served or fetched) can't be purged.
+    pub async fn purge(&self, name: &Path) -> Result<()> {
+        let local = self.named(name);
+
+        let mut index = self.index.lock().await;
+        let cached = index.get(&local).ok_or(Error::NotFound)?;
+        // With the index locked, the only other references to idle repositories are in the index
+        // itself.
+        if Arc::strong_count(&cached.repo) > 1 {
+            return Err(Error::Conflict("repository in use"));
+        }
+        let removed = self.remove(&mut index, &local).await?;
+        drop(index);
+
+        tracing::info!(?local, "purged repository");
+        self.delete(removed).await
+    }
+
+    /// Pin or unpin the repository `name`, persistently.
+    ///
+    /// Repositories pinned with `--pin` can't be unpinned this way.
+    pub async fn pin(&self, name: &Path, pinned: bool) -> Result<Status> {
+        let local = self.named(name);
+        let (repo, status) = {
+            let index = self.index.lock().await;
+            let cached = index.get(&local).ok_or(Error::NotFound)?;
+            {
+                let mut usage = cached.usage.lock().unwrap();
+                if !pinned && usage.pinned {
+                    return Err(Error::Conflict("pinned by configuration"));
+                }
+                usage.pinned_by_admin = pinned;
+            }
+            (cached.repo.clone(), self.status(&local, cached))
+        };
+
+        repo.read().await.save_metadata().await?;
+        tracing::info!(name = ?status.name, pinned, "changed pin");
+        Ok(status)
+    }
+
+    /// The local copy of the repository `name`, as it appears in clone URLs (with or without the
+    /// `.git` extension).
+    ///
+    /// Only used to look up the index, so that it needs no further validation.
+    fn named(&self, name: &Path) -> PathBuf {
+        self.cache_dir.join(name).with_extension("git")
+    }
+
+    fn status(&self, local: &Path, cached: &Cached) -> Status {
+        let usage = cached.usage.lock().unwrap();
+        Status {
+            name: local
+                .strip_prefix(&self.cache_dir)
+                .expect("repository should be in cache_dir")
+                .to_path_buf(),
+            upstream: cached.upstream.clone(),
+            last_access: usage.last_access,
+            last_fetch: usage.last_fetch,
+            size: usage.size,
+            pinned: usage.is_pinned(),
+        }
+    }
+}
+
+/// A repository moved to the trash, to be deleted.
+#[derive(Debug)]
+struct Removed {
+    target: PathBuf,
+    /// The object pool it should leave, and its member ID there.
+    pool: Option<(String, String)>,
+}
+
+/// Find the repositories in `cache_dir`: directories named `*.git` with a metadata file.
+fn find_repos(cache_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    fn walk(dir: &Path, found: &mut Vec<PathBuf>) -> io::Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "git")
+                && path.join(METADATA_FILE).is_file()
+            {
+                found.push(path);
+            } else {
+                walk(&path, found)?;
+            }
+        }
+        Ok(())
+    }
+
+    let mut found = vec![];
+    for entry in std::fs::read_dir(cache_dir)? {
+        let entry = entry?;
+        // Dot-prefixed names at the top of `cache_dir` are reserved for our own use.
+        if entry.file_name().as_encoded_bytes().starts_with(b".") || !entry.file_type()?.is_dir() {
+            continue;
+        }
+        walk(&entry.path(), &mut found)?;
+    }
+    Ok(found)
+}
+
+/// Remove the lock files left behind in the repositories and object pools in `cache_dir`, e.g. by
+/// git processes that had to be killed.
+///
+/// Only safe while no git processes are running on them. Returns how many were removed.
+pub async fn remove_stale_locks(cache_dir: PathBuf) -> Result<usize> {
+    fn walk(dir: &Path, removed: &mut usize) -> io::Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                // Loose objects are never locked, and there may be a lot of them.
+                let loose = dir.file_name().is_some_and(|name| name == "objects")
+                    && entry.file_name().len() == 2;
+                if !loose {
+                    walk(&path, removed)?;
+                }
+            } else if file_type.is_file() && path.extension().is_some_and(|ext| ext == "lock") {
+                tracing::warn!(?path, "removing stale lock file");
+                std::fs::remove_file(&path)?;
+                *removed += 1;
+            }
+        }
+        Ok(())
+    }
+
+    let removed = tokio::task::spawn_blocking(move || {
+        let mut removed = 0;
+        for entry in std::fs::read_dir(&cache_dir)? {
+            let entry = entry?;
+            // Other than object pools, dot-prefixed names hold no repositories.
+            let name = entry.file_name();
+            if (name.as_encoded_bytes().starts_with(b".") && name != pool::POOLS_DIR)
+                || !entry.file_type()?.is_dir()
+            {
+                continue;
+            }
+            walk(&entry.path(), &mut removed)?;
+        }
+        io::Result::Ok(removed)
+    })
+    .await
+    .expect("lock cleanup task should not panic")
+    .context("failed to remove stale lock files")?;
+
+    Ok(removed)
+}
+
+/// How much disk space `path` takes, including everything under it.
+pub async fn disk_usage(path: PathBuf) -> Result<u64> {
+    fn walk(path: &Path) -> io::Result<u64> {
+        let metadata = match std::fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            // Git may be deleting files concurrently.
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err),
+        };
+
+        let mut total = metadata.blocks() * 512;
+        if metadata.is_dir() {
+            for entry in std::fs::read_dir(path)? {
+                total += walk(&entry?.path())?;
+            }
+        }
+        Ok(total)
+    }
+
+    let usage = tokio::task::spawn_blocking(move || walk(&path))
+        .await
+        .expect("disk usage task should not panic")
+        .context("failed to compute disk usage")?;
+
+    Ok(usage)
 }
 
 #[derive(Debug)]
@@ -82,46 +946,360 @@ pub struct Repo {
     git: Arc<Git>,
     upstream: Uri,
     local: PathBuf,
+    /// The path of `local` relative to `cache_dir`.
+    name: PathBuf,
+    cache_dir: PathBuf,
+    sharing: Sharing,
+    /// The ref HEAD points to, as last advertised by the upstream.
+    head: Option<String>,
+    fresh_for: Duration,
+    serve_stale_within: Option<Duration>,
+    disk_quota: Option<u64>,
+    last_fetch: Option<Instant>,
+    /// When the last successful fetch finished.
+    last_fetch_finished: Option<Instant>,
+    /// When the upstream last authorized each client, keyed by a digest of its `Authorization`
+    /// header, or `None` for anonymous clients. Updated by readers, hence the interior mutability.
+    authorized: SyncMutex<HashMap<Option<[u8; 32]>, Instant>>,
+    usage: Arc<SyncMutex<Usage>>,
+    fetched: Arc<Notify>,
+    pools: Arc<Mutex<()>>,
+    maintenance: SyncMutex<Maintenance>,
+}
+
+#[derive(Debug)]
+struct Maintenance {
+    last: Instant,
+    /// Fetches since `last`.
+    fetches: u32,
 }
 
 impl Repo {
-    pub async fn authenticate_with_head(
-        &self,
-        auth: Option<HeaderValue>,
-    ) -> Result<Option<String>> {
+    /// Whether the local copy was fetched recently enough to be served as is.
+    pub fn is_fresh(&self) -> bool {
+        self.last_fetch
+            .is_some_and(|last_fetch| last_fetch.elapsed() < self.fresh_for)
+    }
+
+    pub async fn authenticate_with_head(&self, auth: Option<HeaderValue>) -> Result<Refs> {
         // Assume we (the server) has a modern git that supports symrefs.
-        self.git
-            .authenticate_with_head(self.upstream.clone(), auth)
-            .await
+        let started = Instant::now();
+        let refs = self
+            .git
+            .authenticate_with_head(self.upstream.clone(), auth.clone())
+            .await;
+        METRICS
+            .upstream_refs_duration
+            .observe(started.elapsed().as_secs_f64());
+        let refs = refs?;
+
+        let now = Instant::now();
+        if let Some(within) = self.serve_stale_within {
+            let mut authorized = self.authorized.lock().unwrap();
+            authorized.retain(|_, at| now.duration_since(*at) < within);
+            authorized.insert(auth_key(auth.as_ref()), now);
+        }
+
+        Ok(refs)
     }
 
+    /// Decide whether to serve the local copy as is after the upstream became unavailable.
+    ///
+    /// This requires a previously fetched copy and that the upstream recently authorized either
+    /// this client or anonymous access. Returns how long ago the copy was fetched, or `err` if it
+    /// can't be served.
+    pub fn fall_back_to_stale(
+        &self,
+        auth: Option<&HeaderValue>,
+        err: anyhow::Error,
+    ) -> Result<Duration> {
+        let Some(within) = self.serve_stale_within else {
+            return Err(Error::UpstreamUnavailable(err));
+        };
+        let Some(last_fetch) = self.last_fetch else {
+            return Err(Error::UpstreamUnavailable(err));
+        };
+
+        let authorized = self.authorized.lock().unwrap();
+        let recently_authorized =
+            |key| authorized.get(&key).is_some_and(|at| at.elapsed() < within);
+        if !recently_authorized(None) && !recently_authorized(auth_key(auth)) {
+            return Err(Error::UpstreamUnavailable(err));
+        }
+
+        let age = last_fetch.elapsed();
+        tracing::warn!(
+            upstream = %self.upstream,
+            upstream_error = format_args!("{:#}", err),
+            "upstream unavailable, serving local copy fetched {} ago",
+            humantime::format_duration(Duration::from_secs(age.as_secs())),
+        );
+
+        Ok(age)
+    }
+
+    /// Update the local copy from the upstream.
+    ///
+    /// With the `upstream_refs` the upstream just advertised, running `git fetch` is skipped if
+    /// the local refs already match them.
+    ///
+    /// Concurrent fetches are coalesced: if another fetch succeeded after `coalesce_since`
+    /// (typically because it was running while the caller waited for the lock), there's no need
+    /// to fetch again. Failures aren't shared, since they may be specific to the credentials used.
     pub async fn fetch(
         &mut self,
-        remote_head: Option<String>,
+        upstream_refs: Option<Refs>,
         auth: Option<HeaderValue>,
+        coalesce_since: Option<Instant>,
     ) -> Result<()> {
-        if let Some(remote_head) = remote_head {
-            tokio::fs::write(self.local.join("HEAD"), format!("ref: {remote_head}"))
-                .await
-                .context("failed to update HEAD")?;
+        if let (Some(since), Some(finished)) = (coalesce_since, self.last_fetch_finished) {
+            if finished > since {
+                tracing::debug!("sharing the result of a concurrent fetch");
+                return Ok(());
+            }
         }
 
-        self.git
+        self.fetch_now(upstream_refs, auth).await?;
+        self.last_fetch_finished = Some(Instant::now());
+
+        Ok(())
+    }
+
+    async fn fetch_now(
+        &mut self,
+        upstream_refs: Option<Refs>,
+        auth: Option<HeaderValue>,
+    ) -> Result<()> {
+        let started = Instant::now();
+
+        if let Some(upstream_refs) = upstream_refs {
+            if let Some(remote_head) = upstream_refs.head {
+                tokio::fs::write(self.local.join("HEAD"), format!("ref: {remote_head}"))
+                    .await
+                    .context("failed to update HEAD")?;
+                self.head = Some(remote_head);
+            }
+
+            if self.git.local_refs(self.local.clone()).await? == upstream_refs.refs {
+                tracing::debug!("local refs already match upstream, skipping fetch");
+                self.fetched_at(started);
+                self.save_metadata().await?;
+                return Ok(());
+            }
+        }
+
+        let fetching = Instant::now();
+        let result = self
+            .git
             .fetch(self.upstream.clone(), self.local.clone(), auth)
+            .await;
+        METRICS
+            .fetch_duration
+            .observe(fetching.elapsed().as_secs_f64());
+        result?;
+
+        self.fetched_at(started);
+
+        self.maintenance.get_mut().unwrap().fetches += 1;
+
+        // Not sharing objects is no reason to fail the fetch.
+        if let Err(err) = self.join_pool().await {
+            tracing::warn!(local = ?self.local, error = ?err, "failed to share objects with forks");
+        }
+
+        // Measuring walks the whole repository, so only bother when eviction depends on it.
+        if self.disk_quota.is_some() {
+            let size = disk_usage(self.local.clone()).await?;
+            self.usage.lock().unwrap().size = size;
+        }
+        self.save_metadata().await?;
+        self.fetched.notify_one();
+
+        Ok(())
+    }
+
+    fn fetched_at(&mut self, started: Instant) {
+        self.last_fetch = Some(started);
+        self.usage.lock().unwrap().last_fetch = Some(SystemTime::now() - started.elapsed());
+    }
+
+    /// Start sharing objects with forks through an object pool, if configured to and not yet doing
+    /// so.
+    ///
+    /// The objects themselves are only deduplicated the next time the repository is maintained.
+    async fn join_pool(&mut self) -> Result<()> {
+        let pool = match &self.sharing {
+            Sharing::Off | Sharing::Pool(_) => return Ok(()),
+            Sharing::Group(group) => group.clone(),
+            Sharing::RootCommit => match self.git.root_commit(self.local.clone()).await? {
+                Some(root) => pool::root_commit_name(&root),
+                None => return Ok(()),
+            },
+        };
+
+        // The pool is created if it doesn't exist yet, so it mustn't be deleted in the meantime.
+        let _pools = self.pools.lock().await;
+
+        self.git
+            .sync_pool(
+                pool::path(&self.cache_dir, &pool),
+                self.local.clone(),
+                pool::member_id(&self.name),
+            )
+            .await?;
+
+        let info = self.local.join("objects/info");
+        fs::create_dir_all(&info)
             .await
+            .context("failed to create objects/info")?;
+        fs::write(
+            info.join("alternates"),
+            format!("{}\n", pool::alternates(&self.name, &pool).display()),
+        )
+        .await
+        .context("failed to write objects/info/alternates")?;
+
+        tracing::info!(local = ?self.local, pool, "sharing objects with forks");
+        self.usage.lock().unwrap().pool = Some(pool.clone());
+        self.sharing = Sharing::Pool(pool);
+
+        Ok(())
     }
 
-    pub fn advertise_refs(&self) -> Result<GitAsyncRead> {
-        self.git.advertise_refs(self.local.clone())
+    /// Persist what's needed to restore this repository into the index after a restart.
+    ///
+    /// Accesses that don't involve fetching are not persisted immediately, but only along with
+    /// the next fetch or maintenance.
+    async fn save_metadata(&self) -> Result<()> {
+        let metadata = {
+            let mut usage = self.usage.lock().unwrap();
+            usage.saved_access = usage.last_access;
+            Metadata {
+                upstream: self.upstream.to_string(),
+                head: self.head.clone(),
+                last_fetch: self
+                    .last_fetch
+                    .map(|last_fetch| SystemTime::now() - last_fetch.elapsed()),
+                last_access: usage.last_access,
+                size: usage.size,
+                pinned: usage.pinned_by_admin,
+                pool: match &self.sharing {
+                    Sharing::Pool(pool) => Some(pool.clone()),
+                    _ => None,
+                },
+            }
+        };
+        metadata.write(&self.local).await
     }
 
-    pub async fn upload_pack(&self, input: Bytes) -> Result<GitAsyncRead> {
-        self.git.upload_pack(self.local.clone(), input).await
+    fn maintenance_due(&self, config: &Config) -> bool {
+        let maintenance = self.maintenance.lock().unwrap();
+
+        // Nothing can have changed without fetching.
+        maintenance.fetches > 0
+            && (config
+                .maintenance_interval
+                .is_some_and(|interval| maintenance.last.elapsed() >= interval)
+                || config
+                    .maintenance_after_fetches
+                    .is_some_and(|fetches| maintenance.fetches >= fetches))
     }
+
+    /// Optimize the local copy. Callers should hold (at least) a read lock, to exclude fetches.
+    async fn maintain(&self) -> Result<()> {
+        tracing::info!(local = ?self.local, "running maintenance");
+        let started = Instant::now();
+
+        self.git.maintain(self.local.clone()).await?;
+
+        *self.maintenance.lock().unwrap() = Maintenance {
+            last: Instant::now(),
+            fetches: 0,
+        };
+
+        let size = disk_usage(self.local.clone()).await?;
+        self.usage.lock().unwrap().size = size;
+        self.save_metadata().await?;
+
+        tracing::info!(local = ?self.local, elapsed = ?started.elapsed(), size, "done with maintenance");
+        Ok(())
+    }
+
+    /// Advertise the local refs, keeping `repo` read locked until `git-upload-pack` exits.
+    pub fn advertise_refs(
+        repo: OwnedRwLockReadGuard<Repo>,
+        protocol: Option<String>,
+    ) -> Result<GitAsyncRead> {
+        let (git, local) = (repo.git.clone(), repo.local.clone());
+        git.advertise_refs(local, protocol, Box::new(repo))
+    }
+
+    /// Serve a pack from the local copy, keeping `repo` read locked until `git-upload-pack` exits.
+    pub async fn upload_pack(
+        repo: OwnedRwLockReadGuard<Repo>,
+        protocol: Option<String>,
+        input: GitAsyncRead,
+    ) -> Result<GitAsyncRead> {
+        let (git, local) = (repo.git.clone(), repo.local.clone());
+        git.upload_pack(local, protocol, input, Box::new(repo))
+            .await
+    }
+}
+
+/// Where the metadata of each repository is kept, relative to its local copy.
+const METADATA_FILE: &str = "git-cache.json";
+
+/// What is persisted about a repository, to restore it into the index after a restart.
+#[derive(Debug, Serialize, Deserialize)]
+struct Metadata {
+    upstream: String,
+    head: Option<String>,
+    last_fetch: Option<SystemTime>,
+    last_access: SystemTime,
+    size: u64,
+    /// Pinned through the admin API.
+    #[serde(default)]
+    pinned: bool,
+    /// The object pool shared with forks, if any.
+    pool: Option<String>,
+}
+
+impl Metadata {
+    async fn read(local: &Path) -> Result<Self> {
+        let contents = fs::read(local.join(METADATA_FILE))
+            .await
+            .context("failed to read repository metadata")?;
+        Ok(serde_json::from_slice(&contents).context("failed to parse repository metadata")?)
+    }
+
+    /// Replace the metadata of `local`, atomically.
+    async fn write(&self, local: &Path) -> Result<()> {
+        let contents = serde_json::to_vec_pretty(self).expect("metadata should be serializable");
+        let tmp = local.join(format!("{METADATA_FILE}.tmp"));
+        fs::write(&tmp, contents)
+            .await
+            .context("failed to write repository metadata")?;
+        fs::rename(&tmp, local.join(METADATA_FILE))
+            .await
+            .context("failed to replace repository metadata")?;
+        Ok(())
+    }
+}
+
+/// Convert a wall clock time into an `Instant`, if it isn't too far in the past to represent.
+fn instant_from(time: SystemTime) -> Option<Instant> {
+    let elapsed = SystemTime::now().duration_since(time).unwrap_or_default();
+    Instant::now().checked_sub(elapsed)
+}
+
+fn auth_key(auth: Option<&HeaderValue>) -> Option<[u8; 32]> {
+    auth.map(|auth| Sha256::digest(auth.as_bytes()).into())
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
     use tempfile::tempdir;
 
     use super::*;
@@ -133,7 +1311,7 @@ mod tests {
         let mut mock_git = Git::default();
         mock_git.expect_init().returning(|_| Ok(()));
 
-        let index = Index::new(cache_dir, mock_git);
+        let index = Index::new(cache_dir, mock_git, Default::default());
 
         assert!(index
             .open(Uri::from_static("https://example.com//a/b"))
@@ -154,16 +1332,21 @@ mod tests {
             .open(Uri::from_static("https://example.com/./a/b.git"))
             .await
             .is_err());
+
+        assert!(index
+            .open(Uri::from_static("https://.lfs/a/b.git"))
+            .await
+            .is_err());
     }
 
     #[tokio::test]
-    async fn mutual_exclusion() {
+    async fn locking() {
         let cache_dir = tempdir().unwrap().into_path();
 
         let mut mock_git = Git::default();
         mock_git.expect_init().times(2).returning(|_| Ok(()));
 
-        let index = Index::new(cache_dir, mock_git);
+        let index = Index::new(cache_dir, mock_git, Default::default());
 
         let a = index
             .open("https://example.com/a/b/c".parse().unwrap())
@@ -178,9 +1361,556 @@ mod tests {
             .await
             .unwrap();
 
-        let lock_a = a.lock().await;
-        assert!(b.try_lock().is_err());
-        assert!(c.try_lock().is_ok());
-        drop(lock_a);
+        // Readers share access, but writers are exclusive.
+        let read_a = a.read().await;
+        assert!(b.try_read().is_ok());
+        assert!(b.try_write().is_err());
+        drop(read_a);
+
+        let write_a = a.write().await;
+        assert!(b.try_read().is_err());
+        assert!(c.try_write().is_ok());
+        drop(write_a);
+    }
+
+    #[tokio::test]
+    async fn eviction() {
+        let cache_dir = tempdir().unwrap().into_path();
+
+        let mut mock_git = Git::default();
+        mock_git.expect_init().times(4).returning(|_| Ok(()));
+
+        let index = Index::new(
+            cache_dir.clone(),
+            mock_git,
+            Config {
+                disk_quota: Some(300),
+                pinned: vec![PathBuf::from("example.com/pinned")],
+                ..Default::default()
+            },
+        );
+
+        // From least to most recently used.
+        let mut repos = vec![];
+        for (name, seconds) in [("pinned", 1), ("busy", 2), ("a", 3), ("b", 4)] {
+            let repo = index
+                .open(format!("https://example.com/{name}").parse().unwrap())
+                .await
+                .unwrap();
+
+            let local = cache_dir.join(format!("example.com/{name}.git"));
+            let index = index.index.lock().await;
+            let mut usage = index[&local].usage.lock().unwrap();
+            usage.last_access = SystemTime::UNIX_EPOCH + Duration::from_secs(seconds);
+            usage.size = 100;
+
+            repos.push((local, repo));
+        }
+
+        // Keep only the busy repository in use.
+        let [(pinned, _), (busy, _busy), (a, _), (b, _)]: [_; 4] = repos.try_into().unwrap();
+
+        index.evict().await.unwrap();
+
+        assert!(pinned.exists());
+        assert!(busy.exists());
+        assert!(!a.exists());
+        assert!(b.exists());
+        assert!(!index.index.lock().await.contains_key(&a));
+
+        // Already within quota.
+        index.evict().await.unwrap();
+        assert!(b.exists());
+    }
+
+    #[tokio::test]
+    async fn pool_eviction() {
+        let cache_dir = tempdir().unwrap().into_path();
+        let pool = cache_dir.join(".pools/mock.git");
+        std::fs::create_dir_all(&pool).unwrap();
+        std::fs::write(pool.join("objects"), vec![1; 64 << 10]).unwrap();
+
+        let mut mock_git = Git::default();
+        mock_git.expect_init().times(3).returning(|_| Ok(()));
+        let path = pool.clone();
+        mock_git
+            .expect_leave_pool()
+            .withf(move |p, _| *p == path)
+            .times(2)
+            .returning(|_, _| Ok(()));
+
+        let index = Index::new(
+            cache_dir.clone(),
+            mock_git,
+            Config {
+                disk_quota: Some(1 << 10),
+                ..Default::default()
+            },
+        );
+
+        let mut repos = vec![];
+        for (name, seconds) in [("a", 1), ("b", 2), ("busy", 3)] {
+            let repo = index
+                .open(format!("https://example.com/{name}").parse().unwrap())
+                .await
+                .unwrap();
+            let mut guard = repo.write().await;
+            guard.sharing = Sharing::Pool("mock".into());
+            *guard.usage.lock().unwrap() = Usage {
+                last_access: SystemTime::UNIX_EPOCH + Duration::from_secs(seconds),
+                saved_access: SystemTime::UNIX_EPOCH,
+                last_fetch: None,
+                size: 100,
+                pinned: false,
+                pinned_by_admin: false,
+                pool: Some("mock".into()),
+            };
+            drop(guard);
+            repos.push(repo);
+        }
+
+        // The pool alone is over quota, but it still has a member in use.
+        let busy = repos.pop().unwrap();
+        drop(repos);
+        index.evict().await.unwrap();
+        assert_eq!(index.index.lock().await.len(), 1);
+        assert!(pool.exists());
+
+        // Its last member gone, so is the pool.
+        drop(busy);
+        index.evict().await.unwrap();
+        assert!(index.index.lock().await.is_empty());
+        assert!(!pool.exists());
+    }
+
+    #[tokio::test]
+    async fn maintenance_after_fetches() {
+        let cache_dir = tempdir().unwrap().into_path();
+
+        let mut mock_git = Git::default();
+        mock_git.expect_init().times(1).returning(|_| Ok(()));
+        mock_git.expect_fetch().times(3).returning(|_, _, _| Ok(()));
+        mock_git.expect_maintain().times(1).returning(|_| Ok(()));
+
+        let index = Index::new(
+            cache_dir,
+            mock_git,
+            Config {
+                maintenance_after_fetches: Some(2),
+                ..Default::default()
+            },
+        );
+
+        let repo = index
+            .open("https://example.com/a/b/c".parse().unwrap())
+            .await
+            .unwrap();
+
+        // Due only on the second fetch, and then only again after two more.
+        for _ in 0..3 {
+            repo.write().await.fetch(None, None, None).await.unwrap();
+            index.maintain_due().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn fork_pools() {
+        let cache_dir = tempdir().unwrap().into_path();
+        let pool_a = cache_dir.join(".pools/mock.git");
+        let pool_b = cache_dir.join(".pools/root-1234.git");
+        let (local_a, local_b) = (
+            cache_dir.join("example.com/a.git"),
+            cache_dir.join("example.com/b.git"),
+        );
+
+        let mut mock_git = Git::default();
+        mock_git.expect_init().times(2).returning(|_| Ok(()));
+        mock_git.expect_fetch().times(3).returning(|_, _, _| Ok(()));
+        mock_git
+            .expect_root_commit()
+            .times(1)
+            .returning(|_| Ok(Some("1234".into())));
+
+        // Once to join, and once again before maintaining the pool.
+        for (pool, local) in [(&pool_a, &local_a), (&pool_b, &local_b)] {
+            let (pool, local) = (pool.clone(), local.clone());
+            mock_git
+                .expect_sync_pool()
+                .withf(move |p, l, _| *p == pool && *l == local)
+                .times(2)
+                .returning(|_, _, _| Ok(()));
+        }
+
+        let mut seq = mockall::Sequence::new();
+        for path in [&pool_a, &local_a, &pool_b, &local_b] {
+            let path = path.clone();
+            mock_git
+                .expect_maintain()
+                .withf(move |p| *p == path)
+                .times(1)
+                .in_sequence(&mut seq)
+                .returning(|_| Ok(()));
+        }
+
+        let index = Index::new(
+            cache_dir,
+            mock_git,
+            Config {
+                maintenance_after_fetches: Some(1),
+                fork_groups: [("example.com/a.git".into(), "mock".into())].into(),
+                detect_forks: true,
+                ..Default::default()
+            },
+        );
+
+        let a = index
+            .open("https://example.com/a".parse().unwrap())
+            .await
+            .unwrap();
+        let b = index
+            .open("https://example.com/b".parse().unwrap())
+            .await
+            .unwrap();
+
+        a.write().await.fetch(None, None, None).await.unwrap();
+        b.write().await.fetch(None, None, None).await.unwrap();
+
+        // Already a member.
+        a.write().await.fetch(None, None, None).await.unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(local_a.join("objects/info/alternates")).unwrap(),
+            "../../../.pools/mock.git/objects\n"
+        );
+
+        index.maintain_due().await;
+    }
+
+    #[tokio::test]
+    async fn restore_from_metadata() {
+        let cache_dir = tempdir().unwrap().into_path();
+        let config = || Config {
+            fresh_for: Duration::from_secs(3600),
+            ..Default::default()
+        };
+
+        let mut mock_git = Git::default();
+        mock_git.expect_init().times(2).returning(|_| Ok(()));
+        mock_git
+            .expect_local_refs()
+            .returning(|_| Ok(Default::default()));
+        mock_git.expect_fetch().times(1).returning(|_, _, _| Ok(()));
+
+        let index = Index::new(cache_dir.clone(), mock_git, config());
+        let a = index
+            .open("https://example.com/a".parse().unwrap())
+            .await
+            .unwrap();
+        index
+            .open("https://example.com/b/c".parse().unwrap())
+            .await
+            .unwrap();
+
+        let upstream_refs = Refs {
+            head: Some("refs/heads/main".into()),
+            refs: [("refs/heads/main".into(), "1234".into())].into(),
+        };
+        a.write()
+            .await
+            .fetch(Some(upstream_refs), None, None)
+            .await
+            .unwrap();
+        drop((a, index));
+
+        // Neither have metadata.
+        std::fs::create_dir_all(cache_dir.join("example.com/d.git")).unwrap();
+        std::fs::create_dir_all(cache_dir.join(".pools/e.git")).unwrap();
+
+        let index = Index::new(cache_dir.clone(), Git::default(), config());
+        index.load().await.unwrap();
+        assert_eq!(index.index.lock().await.len(), 2);
+
+        let a = index
+            .open("https://example.com/a".parse().unwrap())
+            .await
+            .unwrap();
+        let a = a.read().await;
+        assert_eq!(a.upstream, "https://example.com/a");
+        assert_eq!(a.head.as_deref(), Some("refs/heads/main"));
+        assert!(a.is_fresh());
+
+        let b = index
+            .open("https://example.com/b/c".parse().unwrap())
+            .await
+            .unwrap();
+        assert!(!b.read().await.is_fresh());
+    }
+
+    #[tokio::test]
+    async fn admin_operations() {
+        let cache_dir = tempdir().unwrap().into_path();
+        let config = || Config {
+            pinned: vec![PathBuf::from("example.com/pinned")],
+            ..Default::default()
+        };
+
+        let mut mock_git = Git::default();
+        mock_git.expect_init().times(2).returning(|_| Ok(()));
+        mock_git.expect_fetch().times(1).returning(|_, _, _| Ok(()));
+
+        let index = Index::new(cache_dir.clone(), mock_git, config());
+        let a = index
+            .open("https://example.com/a".parse().unwrap())
+            .await
+            .unwrap();
+        index
+            .open("https://example.com/pinned".parse().unwrap())
+            .await
+            .unwrap();
+
+        let list = index.list().await;
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].name, Path::new("example.com/a.git"));
+        assert_eq!(list[0].upstream, "https://example.com/a");
+        assert!(list[0].last_fetch.is_none());
+        assert!(!list[0].pinned);
+        assert!(list[1].pinned);
+
+        // Names can omit the extension, like in clone URLs.
+        index.refresh(Path::new("example.com/a")).await.unwrap();
+        let (status, details) = index.details(Path::new("example.com/a")).await.unwrap();
+        assert!(status.last_fetch.is_some());
+        assert_eq!(details.unwrap().local, cache_dir.join("example.com/a.git"));
+        assert!(matches!(
+            index.details(Path::new("example.com/b")).await,
+            Err(Error::NotFound)
+        ));
+
+        // Details aren't available during fetches.
+        let write = a.write().await;
+        let (_, details) = index.details(Path::new("example.com/a")).await.unwrap();
+        assert!(details.is_none());
+        drop(write);
+
+        // Only pins made through the index can be undone.
+        assert!(
+            index
+                .pin(Path::new("example.com/a"), true)
+                .await
+                .unwrap()
+                .pinned
+        );
+        assert!(matches!(
+            index.pin(Path::new("example.com/pinned"), false).await,
+            Err(Error::Conflict(_))
+        ));
+
+        // Repositories in use can't be purged.
+        assert!(matches!(
+            index.purge(Path::new("example.com/a")).await,
+            Err(Error::Conflict(_))
+        ));
+        drop((a, index));
+
+        // Pins persist.
+        let index = Index::new(cache_dir.clone(), Git::default(), config());
+        index.load().await.unwrap();
+        let list = index.list().await;
+        assert!(list.iter().all(|status| status.pinned));
+
+        index.purge(Path::new("example.com/a.git")).await.unwrap();
+        assert!(!cache_dir.join("example.com/a.git").exists());
+        assert_eq!(index.list().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn accesses_saved_periodically() {
+        let cache_dir = tempdir().unwrap().into_path();
+
+        let mut mock_git = Git::default();
+        mock_git.expect_init().times(1).returning(|_| Ok(()));
+
+        let index = Index::new(cache_dir, mock_git, Default::default());
+        let upstream: Uri = "https://example.com/a".parse().unwrap();
+        let repo = index.open(upstream.clone()).await.unwrap();
+        let local = repo.read().await.local.clone();
+        let created = Metadata::read(&local).await.unwrap().last_access;
+
+        // Not saved again right away.
+        index.open(upstream.clone()).await.unwrap();
+        assert_eq!(Metadata::read(&local).await.unwrap().last_access, created);
+
+        // But it is once it's been a while, even without fetching.
+        repo.read().await.usage.lock().unwrap().saved_access = SystemTime::UNIX_EPOCH;
+        index.open(upstream).await.unwrap();
+        assert!(Metadata::read(&local).await.unwrap().last_access > created);
+    }
+
+    #[tokio::test]
+    async fn warming() {
+        let cache_dir = tempdir().unwrap().into_path();
+
+        let mut mock_git = Git::default();
+        mock_git.expect_init().times(1).returning(|_| Ok(()));
+        mock_git.expect_fetch().times(2).returning(|_, _, _| Ok(()));
+
+        let index = Index::new(cache_dir, mock_git, Default::default());
+        let upstream: Uri = "https://example.com/a".parse().unwrap();
+        index.warm(upstream.clone()).await.unwrap();
+
+        let [before] = &index.list().await[..] else {
+            panic!("expected a single repository");
+        };
+        assert!(before.last_fetch.is_some());
+
+        // Warming again doesn't count as an access.
+        index.warm(upstream).await.unwrap();
+        let [after] = &index.list().await[..] else {
+            panic!("expected a single repository");
+        };
+        assert_eq!(after.last_access, before.last_access);
+        assert!(after.last_fetch >= before.last_fetch);
+    }
+
+    #[tokio::test]
+    async fn warming_private_upstream() {
+        let cache_dir = tempdir().unwrap().into_path();
+
+        let mut mock_git = Git::default();
+        mock_git.expect_init().times(1).returning(|_| Ok(()));
+        // The upstream refuses anonymous requests, but the server's own credentials are accepted.
+        mock_git
+            .expect_authenticate_with_head()
+            .returning(|_, _| Err(Error::MissingAuth(HeaderValue::from_static("Basic"))));
+        mock_git
+            .expect_fetch()
+            .withf(|_, _, auth| auth.is_none())
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        let index = Index::new(cache_dir, mock_git, Default::default());
+        index
+            .warm("https://example.com/private".parse().unwrap())
+            .await
+            .unwrap();
+
+        let [repo] = &index.list().await[..] else {
+            panic!("expected a single repository");
+        };
+        assert!(repo.last_fetch.is_some());
+    }
+
+    #[tokio::test]
+    async fn readers_hold_lock_until_child_exits() {
+        let cache_dir = tempdir().unwrap().into_path();
+
+        // Stand-in for the task that waits on the child process.
+        let (reaper, exited) = std::sync::mpsc::channel();
+
+        let mut mock_git = Git::default();
+        mock_git.expect_init().times(1).returning(|_| Ok(()));
+        mock_git
+            .expect_advertise_refs()
+            .times(1)
+            .returning(move |_, _, keep_alive| {
+                reaper.send(keep_alive).unwrap();
+                Ok(Box::new([].as_slice()))
+            });
+
+        let index = Index::new(cache_dir, mock_git, Default::default());
+
+        let repo = index
+            .open("https://example.com/a/b/c".parse().unwrap())
+            .await
+            .unwrap();
+
+        let output = Repo::advertise_refs(repo.clone().read_owned().await, None).unwrap();
+        drop(output);
+        assert!(repo.try_write().is_err());
+
+        drop(exited.recv().unwrap());
+        assert!(repo.try_write().is_ok());
+    }
+
+    #[tokio::test]
+    async fn coalesced_fetches() {
+        let cache_dir = tempdir().unwrap().into_path();
+
+        let mut mock_git = Git::default();
+        mock_git.expect_init().times(1).returning(|_| Ok(()));
+
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        mock_git.expect_fetch().times(4).returning(move |_, _, _| {
+            match counter.fetch_add(1, Ordering::Relaxed) {
+                1 => Err(Error::MissingAuth(HeaderValue::from_static("Basic"))),
+                _ => Ok(()),
+            }
+        });
+
+        let index = Index::new(cache_dir, mock_git, Default::default());
+        let repo = index
+            .open("https://example.com/a/b/c".parse().unwrap())
+            .await
+            .unwrap();
+
+        // Both arrive before either fetches, as if waiting for the lock.
+        let concurrently = || async {
+            let arrived = Instant::now();
+            let tasks = [(), ()].map(|()| {
+                let repo = repo.clone();
+                tokio::spawn(async move {
+                    let mut repo = repo.write().await;
+                    repo.fetch(None, None, Some(arrived)).await
+                })
+            });
+            let mut results = vec![];
+            for task in tasks {
+                results.push(task.await.unwrap().is_ok());
+            }
+            results.sort();
+            results
+        };
+
+        // The first success is shared.
+        assert_eq!(concurrently().await, [true, true]);
+        assert_eq!(fetches.load(Ordering::Relaxed), 1);
+
+        // But not failures, so the other task fetches again.
+        assert_eq!(concurrently().await, [false, true]);
+        assert_eq!(fetches.load(Ordering::Relaxed), 3);
+
+        // Never coalesced.
+        let mut repo = repo.write().await;
+        assert!(repo.fetch(None, None, None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn stale_locks() {
+        let cache_dir = tempdir().unwrap().into_path();
+        let repo = cache_dir.join("example.com/a.git");
+        let pool = pool::path(&cache_dir, "mock");
+        for dir in [
+            repo.join("refs/heads"),
+            pool.join("objects/ab"),
+            cache_dir.join(".lfs"),
+        ] {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        for file in [
+            repo.join("HEAD"),
+            repo.join("packed-refs.lock"),
+            repo.join("refs/heads/main.lock"),
+            pool.join("shallow.lock"),
+            cache_dir.join(".lfs/not-a-repo.lock"),
+        ] {
+            std::fs::write(file, "").unwrap();
+        }
+
+        assert_eq!(remove_stale_locks(cache_dir.clone()).await.unwrap(), 3);
+        assert!(repo.join("HEAD").exists());
+        assert!(!repo.join("refs/heads/main.lock").exists());
+        assert!(!pool.join("shallow.lock").exists());
+        assert!(cache_dir.join(".lfs/not-a-repo.lock").exists());
     }
 }
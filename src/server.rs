@@ -1,24 +1,26 @@
 use std::io;
-use std::iter::once;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use anyhow::Context;
-use axum::body::Body;
+use anyhow::{anyhow, Context};
+use axum::body::{Body, Bytes};
 use axum::extract::{Request, State};
 use axum::http::header;
 use axum::http::{HeaderValue, Method, StatusCode, Uri};
+use axum::middleware;
 use axum::response::{IntoResponse, Response};
-use axum::routing::any;
-use axum::Router;
-use clap::Parser;
-use http_body_util::BodyExt;
+use axum::routing::{any, get};
+use axum::{Extension, Router};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use http_body_util::{BodyExt, LengthLimitError, Limited};
 use tokio::fs;
-use tokio::io::AsyncReadExt;
-use tokio::net::TcpListener;
-use tokio::sync::Mutex;
-use tokio_util::io::ReaderStream;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::sync::RwLock;
+use tokio_util::io::{ReaderStream, StreamReader};
 use tower::ServiceBuilder;
 use tower_http::decompression::RequestDecompressionLayer;
 use tower_http::request_id::{MakeRequestUuid, RequestId};
@@ -27,9 +29,23 @@ use tower_http::set_header::SetResponseHeaderLayer;
 use tower_http::trace::TraceLayer;
 use tower_http::ServiceBuilderExt;
 use tracing::Span;
+use uuid::Uuid;
 
+use crate::admin;
+use crate::cli::Command;
+use crate::config;
 use crate::error::{Error, Result};
-use crate::repo::{Index, Repo};
+use crate::git::{self, GitAsyncRead, UpstreamResponse};
+use crate::lfs;
+use crate::listen::{self, Address, Listener, Owner, UnixOptions};
+use crate::lock::{self, CacheLock};
+use crate::metrics::{self, METRICS};
+use crate::mirror::Mirror;
+use crate::pool;
+use crate::repo::{self, Index, Repo};
+use crate::shutdown::{self, Shutdown};
+use crate::systemd;
+use crate::tls::Certificate;
 
 #[cfg(not(test))]
 use crate::git::Git;
@@ -43,36 +59,434 @@ use crate::APP_NAME;
 #[derive(Clone, Debug, Parser)]
 #[command(version)]
 pub struct Options {
+    /// Run a maintenance command on the cache directory instead of the server.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Read options from a TOML configuration file; command line options take precedence.
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
     /// Location of the git cache.
     #[arg(short, long, default_value = "/var/cache/git", name = "PATH")]
-    cache_dir: PathBuf,
+    pub(crate) cache_dir: PathBuf,
 
-    /// Bind to port.
+    /// Bind to port (on all IPv4 interfaces), unless `--listen` is used.
     #[arg(short, long, default_value = "8080")]
-    port: u16,
+    pub(crate) port: u16,
+
+    /// Listen on ADDR, either `HOST:PORT`, `[IPV6]:PORT` or `unix:PATH` (can be repeated).
+    #[arg(long, value_name = "ADDR")]
+    pub(crate) listen: Vec<Address>,
+
+    /// Set the file mode of Unix domain sockets, in octal.
+    #[arg(long, value_name = "MODE", value_parser = listen::parse_mode)]
+    socket_mode: Option<u32>,
+
+    /// Set the owner of Unix domain sockets.
+    #[arg(long, value_name = "USER[:GROUP]", value_parser = listen::parse_owner)]
+    socket_owner: Option<Owner>,
+
+    /// Serve HTTPS with the PEM certificate chain in FILE, reloaded when it changes; requires
+    /// `--tls-key`.
+    #[arg(long, value_name = "FILE")]
+    pub(crate) tls_cert: Option<PathBuf>,
+
+    /// Read the PEM private key for `--tls-cert` from FILE.
+    #[arg(long, value_name = "FILE")]
+    tls_key: Option<PathBuf>,
+
+    /// Serve the admin API at `/_admin/`, to clients presenting the token in FILE as a bearer
+    /// token.
+    #[arg(long, value_name = "FILE")]
+    admin_token_file: Option<PathBuf>,
+
+    /// Serve the admin API on ADDR instead of the other addresses, in the same formats as
+    /// `--listen` (can be repeated); requires `--admin-token-file`.
+    #[arg(long, value_name = "ADDR")]
+    admin_listen: Vec<Address>,
+
+    /// On SIGTERM or SIGINT, give requests in flight up to DURATION to finish before stopping
+    /// them.
+    #[arg(long, default_value = "30s", value_name = "DURATION", value_parser = humantime::parse_duration)]
+    grace_period: Duration,
+
+    /// Serve repositories fetched less than DURATION ago without updating them first.
+    #[arg(long, default_value = "0s", value_name = "DURATION", value_parser = humantime::parse_duration)]
+    fresh_for: Duration,
+
+    /// Override `--fresh-for` for a specific upstream host (can be repeated).
+    #[arg(long, value_name = "HOST=DURATION", value_parser = parse_host_duration)]
+    host_fresh_for: Vec<(String, Duration)>,
+
+    /// When the upstream is unreachable or failing, serve the last fetched copy instead, but only
+    /// to clients the upstream authorized less than DURATION ago.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    serve_stale_within: Option<Duration>,
+
+    /// Reject request bodies larger than SIZE (after decompression); accepts K, M and G suffixes,
+    /// in either case.
+    #[arg(long, default_value = "64M", value_name = "SIZE", value_parser = config::parse_size)]
+    max_body_size: u64,
+
+    /// Evict the least recently used repositories once they take more than SIZE on disk; accepts
+    /// K, M and G suffixes, in either case.
+    #[arg(long, value_name = "SIZE", value_parser = config::parse_size)]
+    disk_quota: Option<u64>,
+
+    /// Never evict REPO, as it appears in clone URLs, e.g. `github.com/user/repo` (can be
+    /// repeated).
+    #[arg(long, value_name = "REPO")]
+    pin: Vec<PathBuf>,
+
+    /// Run maintenance (repack, commit-graph, prune, etc.) on repositories fetched since, once
+    /// DURATION has passed since the last time.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    maintenance_interval: Option<Duration>,
+
+    /// Run maintenance on repositories after N fetches.
+    #[arg(long, value_name = "N")]
+    maintenance_after: Option<u32>,
+
+    /// Share objects between REPO and the other forks in group NAME, e.g.
+    /// `linux=github.com/torvalds/linux` (can be repeated). Objects are deduplicated during
+    /// maintenance.
+    #[arg(long, value_name = "NAME=REPO", value_parser = parse_fork_group)]
+    fork_group: Vec<(String, PathBuf)>,
+
+    /// Share objects between repositories with the same root commit.
+    #[arg(long, overrides_with = "no_detect_forks")]
+    detect_forks: bool,
+
+    /// Don't share objects between repositories with the same root commit, even if the
+    /// configuration file says to.
+    #[arg(long, overrides_with = "detect_forks")]
+    no_detect_forks: bool,
+
+    /// Fetch REPO in the background every DURATION (can be repeated). REPO is either an upstream
+    /// URL, cloned if not cached yet, or a glob pattern over the cached repositories, as they
+    /// appear in clone URLs, e.g. `github.com/org/*`.
+    #[arg(long, value_name = "REPO=DURATION")]
+    mirror: Vec<Mirror>,
+}
+
+impl Options {
+    /// Parse the command line options, layered over the configuration file (if any), exiting on
+    /// errors.
+    pub fn parse_with_config() -> Self {
+        Self::from_matches(Self::command().get_matches()).unwrap_or_else(|err| err.exit())
+    }
+
+    fn from_matches(matches: ArgMatches) -> std::result::Result<Self, clap::Error> {
+        let mut options = Self::from_arg_matches(&matches)?;
+
+        if let Some(path) = &options.config {
+            let file = config::File::read(path).map_err(|err| {
+                Self::command().error(clap::error::ErrorKind::InvalidValue, format!("{err:#}"))
+            })?;
+            options.layer_over(file, &matches);
+        }
+
+        if options.tls_cert.is_some() != options.tls_key.is_some() {
+            return Err(Self::command().error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "--tls-cert and --tls-key must be used together",
+            ));
+        }
+
+        if !options.admin_listen.is_empty() && options.admin_token_file.is_none() {
+            return Err(Self::command().error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "--admin-listen requires --admin-token-file",
+            ));
+        }
+
+        Ok(options)
+    }
+
+    /// Fill in the options not given on the command line from the configuration `file`.
+    ///
+    /// Repeatable options are merged, with the ones from the command line coming last.
+    fn layer_over(&mut self, file: config::File, matches: &ArgMatches) {
+        let from_file = |id| matches.value_source(id) != Some(ValueSource::CommandLine);
+
+        if let Some(cache_dir) = file.cache_dir.filter(|_| from_file("PATH")) {
+            self.cache_dir = cache_dir;
+        }
+        if let Some(port) = file.port.filter(|_| from_file("port")) {
+            self.port = port;
+        }
+        if let Some(fresh_for) = file.fresh_for.filter(|_| from_file("fresh_for")) {
+            self.fresh_for = fresh_for;
+        }
+        if let Some(max_body_size) = file.max_body_size.filter(|_| from_file("max_body_size")) {
+            self.max_body_size = max_body_size;
+        }
+        if let Some(grace_period) = file.grace_period.filter(|_| from_file("grace_period")) {
+            self.grace_period = grace_period;
+        }
+
+        self.socket_mode = self.socket_mode.or(file.socket_mode);
+        self.socket_owner = self.socket_owner.take().or(file.socket_owner);
+        self.tls_cert = self.tls_cert.take().or(file.tls_cert);
+        self.tls_key = self.tls_key.take().or(file.tls_key);
+        self.admin_token_file = self.admin_token_file.take().or(file.admin_token_file);
+        self.serve_stale_within = self.serve_stale_within.or(file.serve_stale_within);
+        self.disk_quota = self.disk_quota.or(file.disk_quota);
+        self.maintenance_interval = self.maintenance_interval.or(file.maintenance_interval);
+        self.maintenance_after = self.maintenance_after.or(file.maintenance_after);
+        if let Some(detect_forks) = file
+            .detect_forks
+            .filter(|_| from_file("detect_forks") && from_file("no_detect_forks"))
+        {
+            self.detect_forks = detect_forks;
+        }
+
+        let host_fresh_for = file
+            .hosts
+            .into_iter()
+            .filter_map(|(host, options)| Some((host, options.fresh_for?)));
+        self.host_fresh_for = host_fresh_for
+            .chain(self.host_fresh_for.drain(..))
+            .collect();
+
+        self.listen = file
+            .listen
+            .into_iter()
+            .chain(self.listen.drain(..))
+            .collect();
+        self.admin_listen = file
+            .admin_listen
+            .into_iter()
+            .chain(self.admin_listen.drain(..))
+            .collect();
+        self.pin = file.pin.into_iter().chain(self.pin.drain(..)).collect();
+
+        let fork_group = file
+            .fork_groups
+            .into_iter()
+            .flat_map(|(name, repos)| repos.into_iter().map(move |repo| (name.0.clone(), repo)));
+        self.fork_group = fork_group.chain(self.fork_group.drain(..)).collect();
+        self.mirror = file
+            .mirror
+            .into_iter()
+            .chain(self.mirror.drain(..))
+            .collect();
+    }
+
+    /// How repositories are kept up to date, by the server or by the maintenance commands.
+    pub(crate) fn repo_config(&self) -> repo::Config {
+        repo::Config {
+            fresh_for: self.fresh_for,
+            host_fresh_for: self.host_fresh_for.iter().cloned().collect(),
+            serve_stale_within: self.serve_stale_within,
+            disk_quota: self.disk_quota,
+            pinned: self
+                .pin
+                .iter()
+                .cloned()
+                .chain(self.mirror.iter().filter_map(Mirror::pinned))
+                .collect(),
+            maintenance_interval: self.maintenance_interval,
+            maintenance_after_fetches: self.maintenance_after,
+            fork_groups: self
+                .fork_group
+                .iter()
+                .map(|(name, repo)| (repo.with_extension("git"), name.clone()))
+                .collect(),
+            detect_forks: self.detect_forks,
+        }
+    }
+}
+
+fn parse_host_duration(s: &str) -> std::result::Result<(String, Duration), String> {
+    let (host, duration) = s.split_once('=').ok_or("expected HOST=DURATION")?;
+    let duration = humantime::parse_duration(duration).map_err(|err| err.to_string())?;
+    Ok((host.to_string(), duration))
+}
+
+fn parse_fork_group(s: &str) -> std::result::Result<(String, PathBuf), String> {
+    let (name, repo) = s.split_once('=').ok_or("expected NAME=REPO")?;
+    if !pool::is_valid_name(name) {
+        return Err(format!("invalid group name: {name}"));
+    }
+    Ok((name.to_string(), repo.into()))
 }
 
+/// How long git processes get to exit after SIGTERM on shutdown, before being killed.
+const CHILD_EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub async fn start(options: &Options) -> io::Result<()> {
-    let app = app(options, Git::default()).await?;
+    // Sockets passed by systemd (socket activation) take the place of `--listen` and `--port`.
+    let inherited = systemd::listeners()?;
+
+    let shutdown = Shutdown::default();
+    let app = app(options, Git::default(), shutdown.clone()).await?;
+
+    let tls = match (&options.tls_cert, &options.tls_key) {
+        (Some(cert), Some(key)) => {
+            let certificate = Certificate::load(cert, key).map_err(|err| {
+                io::Error::new(err.kind(), format!("failed to load TLS certificate: {err}"))
+            })?;
+            tokio::spawn(certificate.clone().reload_on_change());
+            Some(certificate.acceptor()?)
+        }
+        _ => None,
+    };
+
+    let listeners = if inherited.is_empty() {
+        let addresses = match &options.listen[..] {
+            [] => vec![Address::Tcp(format!("0.0.0.0:{}", options.port))],
+            addresses => addresses.to_vec(),
+        };
+        bind(&addresses, options).await?
+    } else {
+        inherited
+    };
+    let admin_listeners = bind(&options.admin_listen, options).await?;
+
+    let mut servers = vec![];
+    for listener in listeners {
+        match tls {
+            Some(_) => tracing::info!("Listening on {listener} (TLS)"),
+            None => tracing::info!("Listening on {listener}"),
+        }
+        servers.push(listen::serve(
+            listener,
+            app.clone(),
+            tls.clone(),
+            shutdown.clone(),
+        ));
+    }
+    for listener in admin_listeners {
+        tracing::info!("Listening on {listener} (admin API)");
+        servers.push(listen::serve(
+            listener,
+            app.clone().layer(Extension(admin::Listener)),
+            tls.clone(),
+            shutdown.clone(),
+        ));
+    }
+
+    let signaled = shutdown.clone();
+    tokio::spawn(async move {
+        shutdown::signal_received().await;
+        signaled.start();
+    });
+
+    // By now the cache directory is locked and the index is loaded.
+    systemd::notify("READY=1");
+    tokio::spawn(systemd::watchdog());
+
+    futures_util::future::join_all(servers).await;
+
+    // Let the requests in flight finish, unless signaled again.
+    systemd::notify("STOPPING=1");
+    tracing::info!(grace_period = ?options.grace_period, "Shutting down");
+    let drained = tokio::select! {
+        drained = tokio::time::timeout(options.grace_period, shutdown.drained()) => drained.is_ok(),
+        _ = shutdown::signal_received() => false,
+    };
+    if !drained {
+        tracing::warn!("stopping requests still in flight");
+    }
+
+    // Then stop any git processes still running, once no more can be started: the background
+    // tasks (maintenance, eviction, mirroring) stopped as shutdown started, and killing children
+    // refuses new ones. Only then clean up after them. The cache directory stays locked until
+    // `app` is dropped.
+    shutdown.stopped().await;
+    if git::kill_children(CHILD_EXIT_TIMEOUT).await {
+        repo::remove_stale_locks(options.cache_dir.clone())
+            .await
+            .map_err(io::Error::other)?;
+    } else {
+        tracing::error!("git processes still running, leaving their lock files behind");
+    }
 
-    let listener = TcpListener::bind(("0.0.0.0", options.port)).await?;
-    tracing::info!("Listening on {}", listener.local_addr()?);
+    drop(app);
+    tracing::info!("Done");
+    Ok(())
+}
 
-    axum::serve(listener, app).await
+async fn bind(addresses: &[Address], options: &Options) -> io::Result<Vec<Listener>> {
+    let unix = UnixOptions {
+        mode: options.socket_mode,
+        owner: options.socket_owner.clone(),
+    };
+
+    let mut listeners = vec![];
+    for address in addresses {
+        let listener = Listener::bind(address, &unix).await.map_err(|err| {
+            io::Error::new(err.kind(), format!("failed to bind to {address}: {err}"))
+        })?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
 }
 
-async fn app(options: &Options, git: Git) -> io::Result<Router> {
+async fn app(options: &Options, git: Git, shutdown: Shutdown) -> io::Result<Router> {
     // Ensure `cache_dir` exists and acquire a lock on it.
     fs::create_dir_all(&options.cache_dir).await?;
-    fs::write(&options.cache_dir.join(".git-cache"), "").await?; // FIXME: lock
+    let lock = CacheLock::acquire(&options.cache_dir, lock::Mode::Exclusive)?;
     tracing::info!("Cache directory is {:?}", options.cache_dir);
 
-    let index = Index::new(options.cache_dir.clone(), git);
+    let admin_token = match &options.admin_token_file {
+        Some(path) => Some(admin::Token::read(path).map_err(|err| {
+            io::Error::new(err.kind(), format!("failed to read admin token: {err}"))
+        })?),
+        None => None,
+    };
+
+    let state = AppState {
+        repos: Arc::new(Index::new(
+            options.cache_dir.clone(),
+            git,
+            options.repo_config(),
+        )),
+        lfs: lfs::Store::new(options.cache_dir.join(".lfs")),
+        max_body_size: options.max_body_size,
+        tls: options.tls_cert.is_some(),
+        cache_dir: options.cache_dir.clone(),
+        lock,
+        shutdown,
+    };
+    let state = Arc::new(state);
+
+    state.repos.load().await.map_err(io::Error::other)?;
+
+    if options.disk_quota.is_some() {
+        let repos = state.repos.clone();
+        state
+            .shutdown
+            .spawn(async move { repos.evict_after_fetches().await });
+    }
+
+    if options.maintenance_interval.is_some() || options.maintenance_after.is_some() {
+        let repos = state.repos.clone();
+        state
+            .shutdown
+            .spawn(async move { repos.maintain_periodically().await });
+    }
+
+    for mirror in &options.mirror {
+        state
+            .shutdown
+            .spawn(mirror.clone().run(state.repos.clone()));
+    }
 
     // TODO: delegate more to the axum router
-    let router = Router::new()
+    let mut router = Router::new()
+        .route("/metrics", get(handle_metrics))
+        .route("/healthz", get(handle_liveness))
+        .route("/readyz", get(handle_readiness))
         .route("/*req", any(router))
-        .with_state(Arc::new(index));
+        .with_state(state.clone());
+    if let Some(token) = admin_token {
+        let separate_listener = !options.admin_listen.is_empty();
+        router = router.merge(admin::router(state.repos.clone(), token, separate_listener));
+    }
+    let router = router.layer(middleware::from_fn(metrics::count_requests));
 
     let trace_layer = TraceLayer::new_for_http()
         .make_span_with(|request: &Request<_>| {
@@ -104,9 +518,10 @@ async fn app(options: &Options, git: Git) -> io::Result<Router> {
     let middleware = ServiceBuilder::new()
         // WARN: Will *not* overwrite `x-request-id` if already present.
         .set_x_request_id(MakeRequestUuid)
-        .layer(SetSensitiveRequestHeadersLayer::new(once(
+        .layer(SetSensitiveRequestHeadersLayer::new([
             header::AUTHORIZATION,
-        )))
+            lfs::GRANT_HEADER,
+        ]))
         .layer(trace_layer)
         .layer(RequestDecompressionLayer::new())
         .propagate_x_request_id()
@@ -118,63 +533,160 @@ async fn app(options: &Options, git: Git) -> io::Result<Router> {
     Ok(router.layer(middleware))
 }
 
-async fn router(State(repos): State<Arc<Index>>, request: Request<Body>) -> Result<Response> {
+#[derive(Debug)]
+struct AppState {
+    repos: Arc<Index>,
+    lfs: lfs::Store,
+    max_body_size: u64,
+    /// Whether clients reach us over HTTPS (terminated by ourselves).
+    tls: bool,
+    cache_dir: PathBuf,
+    /// Held for as long as the server runs.
+    lock: CacheLock,
+    shutdown: Shutdown,
+}
+
+async fn router(State(state): State<Arc<AppState>>, request: Request<Body>) -> Result<Response> {
+    let repos = &state.repos;
+    let path = request.uri().path();
+
     if request.method() == Method::GET {
-        if request.uri().query() != Some("service=git-upload-pack") {
-            return Err(Error::NotFound);
+        if let Some(path) = path.strip_suffix("/info/refs") {
+            let upstream = upstream(path)?;
+
+            match request.uri().query() {
+                Some("service=git-upload-pack") => {
+                    let repo = repos.open(upstream).await?;
+                    handle_ref_discovery(repo, request).await
+                }
+                Some("service=git-receive-pack") => {
+                    handle_receive_pack_discovery(repos, upstream, request).await
+                }
+                _ => Err(Error::NotFound),
+            }
+        } else if let Some((_, oid)) = path.rsplit_once("/info/lfs/objects/") {
+            let oid = oid.to_string();
+            handle_lfs_download(&state, &oid, request).await
+        } else {
+            Err(Error::NotFound)
         }
-
-        let upstream = request
-            .uri()
-            .path()
-            .strip_suffix("/info/refs")
-            .ok_or(Error::NotFound)?;
-        let upstream: Uri = format!("https:/{}", upstream)
-            .parse()
-            .map_err(|_| Error::NotFound)?;
-
-        let repo = repos.open(upstream).await?;
-        handle_ref_discovery(repo, request).await
     } else if request.method() == Method::POST {
-        let upstream = request
-            .uri()
-            .path()
-            .strip_suffix("/git-upload-pack")
-            .ok_or(Error::NotFound)?;
-        let upstream: Uri = format!("https:/{}", upstream)
-            .parse()
-            .map_err(|_| Error::NotFound)?;
-
-        let repo = repos.open(upstream).await?;
-        handle_upload_pack(repo, request).await
+        if let Some(path) = path.strip_suffix("/git-upload-pack") {
+            let repo = repos.open(upstream(path)?).await?;
+            handle_upload_pack(repo, request, state.max_body_size).await
+        } else if let Some(path) = path.strip_suffix("/git-receive-pack") {
+            let upstream = upstream(path)?;
+            handle_receive_pack(&state, upstream, request).await
+        } else if let Some(path) = path.strip_suffix("/info/lfs/objects/batch") {
+            let upstream = upstream(path)?;
+            handle_lfs_batch(&state, upstream, request).await
+        } else {
+            Err(Error::NotFound)
+        }
     } else {
         Err(Error::NotFound)
     }
 }
 
+async fn handle_metrics(State(state): State<Arc<AppState>>) -> Response {
+    let (repositories, disk_usage) = state.repos.usage().await;
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, metrics::MEDIA_TYPE)],
+        METRICS.encode(repositories, disk_usage),
+    )
+        .into_response()
+}
+
+async fn handle_liveness() -> &'static str {
+    "ok"
+}
+
+/// Whether we can serve requests: the cache directory is locked and writable, git can be run, and
+/// we're not shutting down.
+async fn handle_readiness(State(state): State<Arc<AppState>>) -> Response {
+    let mut failed = vec![];
+
+    if state.shutdown.is_started() {
+        failed.push("shutting down");
+    }
+    if !state.lock.is_held() {
+        failed.push("cache directory not locked");
+    }
+    let probe = state.cache_dir.join(format!(".probe-{}", Uuid::new_v4()));
+    if let Err(err) = async {
+        fs::write(&probe, "").await?;
+        fs::remove_file(&probe).await
+    }
+    .await
+    {
+        tracing::warn!(error = ?err, "cache directory not writable");
+        failed.push("cache directory not writable");
+    }
+    if let Err(err) = state.repos.git().check().await {
+        tracing::warn!(error = ?err, "git can't be run");
+        failed.push("git can't be run");
+    }
+
+    if failed.is_empty() {
+        (StatusCode::OK, "ready").into_response()
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, failed.join(", ")).into_response()
+    }
+}
+
+fn upstream(path: &str) -> Result<Uri> {
+    format!("https:/{}", path)
+        .parse()
+        .map_err(|_| Error::NotFound)
+}
+
 // "Smart" protocol client step 1: ref discovery.
-async fn handle_ref_discovery(repo: Arc<Mutex<Repo>>, request: Request) -> Result<Response> {
-    // FIXME: should only drop this guard after child git-upload-pack exits.
-    let mut repo = repo.lock().await;
+async fn handle_ref_discovery(repo: Arc<RwLock<Repo>>, request: Request) -> Result<Response> {
+    // Any fetch that finishes while we wait for the lock is recent enough to share.
+    let arrived = Instant::now();
 
-    // Authenticate and fetch the remote head (if available).
     let auth = request.headers().get(header::AUTHORIZATION).cloned();
-    let remote_head = repo.authenticate_with_head(auth.clone()).await?;
+    let protocol = git_protocol(&request)?;
 
-    // Clone or update local copy from upstream.
-    repo.fetch(remote_head, auth).await?;
+    // Authenticate and fetch the remote head (if available).
+    let guard = repo.clone().read_owned().await;
+    let result = guard.authenticate_with_head(auth.clone()).await;
+
+    // Clone or update local copy from upstream, unless that was done recently enough.
+    let (guard, result) = match result {
+        Ok(_) if guard.is_fresh() => {
+            tracing::debug!("local copy is still fresh, skipping fetch");
+            (guard, Ok(()))
+        }
+        Ok(upstream_refs) => {
+            // Fetching requires exclusive access, so trade the read lock for a write lock.
+            drop(guard);
+            let mut guard = repo.write_owned().await;
+            let result = guard
+                .fetch(Some(upstream_refs), auth.clone(), Some(arrived))
+                .await;
+            (guard.downgrade(), result)
+        }
+        Err(err) => (guard, Err(err)),
+    };
+    let stale = or_stale(&guard, auth.as_ref(), result)?;
 
     // Advertise refs to client.
     //
-    // According to the specs (see `gitprotocol-http(5)`), if the request includes the
-    // `Git-Protocol: version=1` header an extra PKT_LINE `000dversion 1` shoule be inserted before
-    // the first ref. However, GitHub doesn't implement that, and neither do we: it should just
-    // look like we only support version 1, which is true.
-    let stdout = repo.advertise_refs()?;
-    let output = b"001e# service=git-upload-pack\n0000".chain(stdout);
+    // Like `git-http-backend`, pass the requested protocol version on to git-upload-pack, which
+    // then takes care of the `version 1` line or of the v2 capability advertisement. The service
+    // header is only sent for v0 and v1.
+    let header: &[u8] = if protocol.as_deref().is_some_and(is_v2) {
+        b""
+    } else {
+        b"001e# service=git-upload-pack\n0000"
+    };
+    let stdout = Repo::advertise_refs(guard, protocol)?;
+    let output = header.chain(stdout);
     let output = ReaderStream::new(output);
 
-    Ok((
+    let response = (
         StatusCode::OK,
         [
             (
@@ -185,65 +697,381 @@ async fn handle_ref_discovery(repo: Arc<Mutex<Repo>>, request: Request) -> Resul
         ],
         Body::from_stream(output),
     )
-        .into_response())
+        .into_response();
+
+    Ok(mark_stale(response, stale))
 }
 
 // "Smart" protocol client step 2: compute.
-async fn handle_upload_pack(repo: Arc<Mutex<Repo>>, request: Request) -> Result<Response> {
-    // FIXME: should only drop this guard after child git-upload-pack exits.
-    let repo = repo.lock().await;
-
-    // Authenticate (discard the remote head).
+async fn handle_upload_pack(
+    repo: Arc<RwLock<Repo>>,
+    request: Request,
+    max_body_size: u64,
+) -> Result<Response> {
+    let guard = repo.read_owned().await;
+
+    // Authenticate (discard the upstream refs).
     let auth = request.headers().get(header::AUTHORIZATION).cloned();
-    let _ = repo.authenticate_with_head(auth).await?;
+    let protocol = git_protocol(&request)?;
+    let result = guard.authenticate_with_head(auth.clone()).await.map(drop);
+    let stale = or_stale(&guard, auth.as_ref(), result)?;
 
     // Assume this request immediately follows a ref-discovery step, in which we updated our copy
     // of the repository. If this isn't the case (if the client is broken), we'll simply reply with
     // outdated or no data.
 
-    // FIXME: missing any type of safety limit on the body size
-    // TODO: pipe the client body into git-upload-pack stdin instead of reading all beforehand
+    // Proxy git-upload-pack, piping the request body into it as it arrives.
+    let (input, exceeded) = limited_reader(request, max_body_size)?;
+    let output = Repo::upload_pack(guard, protocol, input).await?;
 
-    // Proxy git-upload-pack.
-    let input = request
-        .into_body()
-        .collect()
-        .await
-        .context("failed to collect the request body")?
-        .to_bytes();
-    let output = repo.upload_pack(input).await?;
-    let output = ReaderStream::new(output);
+    // Only commit to a response status once git-upload-pack starts replying, so that a body
+    // already found to be too large by then still gets a 413.
+    let mut output = BufReader::new(output);
+    let peeked = output.fill_buf().await.map(|buf| buf.len());
+    if exceeded.load(Ordering::Acquire) {
+        return Err(Error::PayloadTooLarge);
+    }
+    peeked.context("failed to read from git-upload-pack")?;
+
+    // git-upload-pack may start replying before it has read all of its input, though. Going over
+    // the limit after that aborts the response, which drops (and so kills) git-upload-pack.
+    let aborted = exceeded.clone();
+    let output = ReaderStream::new(output)
+        .inspect_ok(|chunk| {
+            METRICS.upload_pack_bytes.inc_by(chunk.len() as u64);
+        })
+        .map(move |chunk| match exceeded.load(Ordering::Acquire) {
+            true => Err(body_too_large()),
+            false => chunk,
+        })
+        .chain(
+            stream::once(async move { aborted.load(Ordering::Acquire) })
+                .filter_map(|aborted| async move { aborted.then(|| Err(body_too_large())) }),
+        );
 
-    Ok((
+    let response = (
         StatusCode::OK,
         [
             (header::CONTENT_TYPE, "application/x-git-upload-pack-result"),
             (header::CACHE_CONTROL, "no-cache"),
         ],
         Body::from_stream(output),
+    )
+        .into_response();
+
+    Ok(mark_stale(response, stale))
+}
+
+fn body_too_large() -> io::Error {
+    io::Error::other("request body too large")
+}
+
+/// Limit the request body to `limit` bytes.
+///
+/// Requests that declare a larger `Content-Length` are rejected right away, without reading them.
+fn limited_body(request: Request, limit: u64) -> Result<Limited<Body>> {
+    let content_length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<u64>().ok());
+    if content_length.is_some_and(|len| len > limit) {
+        return Err(Error::PayloadTooLarge);
+    }
+
+    let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+    Ok(Limited::new(request.into_body(), limit))
+}
+
+/// Read the whole request body, but only up to `limit` bytes.
+async fn read_limited(request: Request, limit: u64) -> Result<Bytes> {
+    let body = limited_body(request, limit)?
+        .collect()
+        .await
+        .map_err(|err| match err.downcast::<LengthLimitError>() {
+            Ok(_) => Error::PayloadTooLarge,
+            Err(err) => anyhow!(err)
+                .context("failed to read the request body")
+                .into(),
+        })?;
+    Ok(body.to_bytes())
+}
+
+/// Stream the request body, but only up to `limit` bytes.
+///
+/// Going over the limit fails the read, and sets the returned flag.
+fn limited_reader(request: Request, limit: u64) -> Result<(GitAsyncRead, Arc<AtomicBool>)> {
+    let exceeded = Arc::new(AtomicBool::new(false));
+    let flag = exceeded.clone();
+
+    let body = Body::new(limited_body(request, limit)?).into_data_stream();
+    let body = TryStreamExt::map_err(body, move |err| {
+        let err = err.into_inner();
+        if err.is::<LengthLimitError>() {
+            flag.store(true, Ordering::Release);
+        }
+        io::Error::other(err)
+    });
+
+    Ok((Box::new(StreamReader::new(body)), exceeded))
+}
+
+/// Fall back to the local copy if `result` only failed because the upstream is unavailable.
+///
+/// Returns how long ago the local copy was fetched if it's being served stale.
+fn or_stale(
+    repo: &Repo,
+    auth: Option<&HeaderValue>,
+    result: Result<()>,
+) -> Result<Option<Duration>> {
+    match result {
+        Ok(()) => Ok(None),
+        Err(Error::UpstreamUnavailable(err)) => repo.fall_back_to_stale(auth, err).map(Some),
+        Err(err) => Err(err),
+    }
+}
+
+/// Let the client know that a response comes from a local copy that couldn't be updated.
+fn mark_stale(mut response: Response, stale: Option<Duration>) -> Response {
+    if let Some(age) = stale {
+        let headers = response.headers_mut();
+        headers.insert(header::AGE, HeaderValue::from(age.as_secs()));
+        headers.insert(
+            header::WARNING,
+            HeaderValue::from_static("110 - \"Response is Stale\""),
+        );
+    }
+    response
+}
+
+// Push client step 1: ref discovery, proxied to the upstream.
+async fn handle_receive_pack_discovery(
+    repos: &Index,
+    upstream: Uri,
+    request: Request,
+) -> Result<Response> {
+    let auth = request.headers().get(header::AUTHORIZATION).cloned();
+    let protocol = git_protocol(&request)?;
+
+    let response = repos
+        .git()
+        .receive_pack_advertisement(upstream, auth, protocol)
+        .await?;
+
+    Ok(relay(response))
+}
+
+// Push client step 2: send the pack, also proxied to the upstream.
+async fn handle_receive_pack(
+    state: &AppState,
+    upstream: Uri,
+    request: Request,
+) -> Result<Response> {
+    let auth = request.headers().get(header::AUTHORIZATION).cloned();
+    let protocol = git_protocol(&request)?;
+
+    let response = state
+        .repos
+        .git()
+        .receive_pack(
+            upstream.clone(),
+            auth.clone(),
+            protocol,
+            request.into_body(),
+        )
+        .await?;
+
+    // Update our copy (if any) in the background, so that the pushed commits can be fetched from
+    // the cache soon, without delaying the response. The push itself already went through, so a
+    // failure here shouldn't be reported to the client as one.
+    if response.status == StatusCode::OK {
+        if let Ok(Some(repo)) = state.repos.get(&upstream).await {
+            state.shutdown.spawn(async move {
+                let mut repo = repo.write().await;
+                // Concurrent fetches may have started before the push, so don't coalesce with them.
+                if let Err(err) = repo.fetch(None, auth, None).await {
+                    tracing::warn!(error = ?err, "failed to update local copy after push");
+                }
+            });
+        }
+    }
+
+    Ok(relay(response))
+}
+
+// LFS client step 1: batch request, authorized by the upstream.
+//
+// Downloads are redirected to us, so that objects can be served from (and stored in) the cache.
+// Uploads go straight to wherever the upstream tells the client to send them.
+async fn handle_lfs_batch(state: &AppState, upstream: Uri, request: Request) -> Result<Response> {
+    let auth = request.headers().get(header::AUTHORIZATION).cloned();
+    let objects_url = format!(
+        "{}{}",
+        base_url(&request, state.tls)?,
+        request
+            .uri()
+            .path()
+            .strip_suffix("/batch")
+            .expect("path should end with /info/lfs/objects/batch"),
+    );
+
+    let input = read_limited(request, state.max_body_size).await?;
+
+    let batch: lfs::BatchRequest = serde_json::from_slice(&input)
+        .map_err(|_| Error::BadRequest("invalid LFS batch request"))?;
+
+    let response = state.repos.git().lfs_batch(upstream, auth, input).await?;
+
+    if batch.operation != lfs::Operation::Download || response.status != StatusCode::OK {
+        return Ok(relay(response));
+    }
+
+    let mut batch: lfs::BatchResponse = serde_json::from_slice(&response.body)
+        .context("failed to parse LFS batch response from upstream")?;
+
+    state.lfs.cache_downloads(&mut batch, &objects_url);
+
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, lfs::MEDIA_TYPE)],
+        serde_json::to_vec(&batch).context("failed to serialize LFS batch response")?,
     )
         .into_response())
 }
 
+// LFS client step 2: download an object, with a grant from a previous batch request.
+async fn handle_lfs_download(state: &AppState, oid: &str, request: Request) -> Result<Response> {
+    let download = request
+        .headers()
+        .get(lfs::GRANT_HEADER)
+        .and_then(|token| token.to_str().ok())
+        .and_then(|token| state.lfs.redeem(token, oid))
+        .ok_or(Error::NotFound)?;
+
+    let headers = [
+        (
+            header::CONTENT_TYPE,
+            String::from("application/octet-stream"),
+        ),
+        (header::CONTENT_LENGTH, download.size.to_string()),
+    ];
+
+    if let Some(file) = state.lfs.open(oid).await? {
+        return Ok((
+            StatusCode::OK,
+            headers,
+            Body::from_stream(ReaderStream::new(file)),
+        )
+            .into_response());
+    }
+
+    // Send the object to the client as it's downloaded, while also storing it. It's only added to
+    // the cache once it's been fully received and verified.
+    tracing::info!(oid, "LFS object not cached, downloading from upstream");
+    let input = state.repos.git().lfs_download(download.upstream).await?;
+    let pending = state.lfs.begin(oid, download.size).await?;
+
+    let output = stream::try_unfold(
+        (ReaderStream::new(input), pending),
+        |(mut input, mut pending)| async move {
+            match input.next().await {
+                Some(chunk) => {
+                    let chunk = chunk?;
+                    pending.write(&chunk).await.map_err(io::Error::other)?;
+                    Ok::<_, io::Error>(Some((chunk, (input, pending))))
+                }
+                None => {
+                    pending.commit().await.map_err(io::Error::other)?;
+                    Ok(None)
+                }
+            }
+        },
+    );
+
+    Ok((StatusCode::OK, headers, Body::from_stream(output)).into_response())
+}
+
+/// The URL at which the client reached us, for the links we hand out.
+fn base_url(request: &Request, tls: bool) -> Result<String> {
+    let host = match request.uri().authority() {
+        Some(authority) => authority.as_str(),
+        None => request
+            .headers()
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .ok_or(Error::BadRequest("missing or invalid host header"))?,
+    };
+
+    let scheme = if tls {
+        "https"
+    } else {
+        match request.headers().get("x-forwarded-proto") {
+            Some(proto) if proto == "https" => "https",
+            _ => "http",
+        }
+    };
+
+    Ok(format!("{scheme}://{host}"))
+}
+
+fn relay(response: UpstreamResponse) -> Response {
+    (response.status, response.headers, response.body).into_response()
+}
+
+fn git_protocol(request: &Request) -> Result<Option<String>> {
+    let Some(protocol) = request.headers().get("git-protocol") else {
+        return Ok(None);
+    };
+
+    let protocol = protocol.to_str().map_err(|_| {
+        Error::BadRequest("git-protocol header should contain only visible ASCII chars")
+    })?;
+
+    Ok(Some(protocol.to_string()))
+}
+
+fn is_v2(protocol: &str) -> bool {
+    protocol.split(':').any(|param| param == "version=2")
+}
+
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeMap;
     use std::io::Write;
 
-    use axum::body::Bytes;
+    use axum::http::HeaderMap;
+
+    use crate::git::Refs;
     use flate2::{write::GzEncoder, Compression};
     use http_body_util::BodyExt;
-    use mockall::predicate::eq;
+    use mockall::predicate::{always, eq};
     use tempfile::tempdir;
     use tower::{Service, ServiceExt};
 
     use super::*;
 
+    fn mock_refs() -> Refs {
+        Refs {
+            head: Some(String::from("refs/heads/mock")),
+            refs: [(String::from("refs/heads/mock"), "1".repeat(40))].into(),
+        }
+    }
+
+    fn options(args: &[&str]) -> Options {
+        let cache_dir = tempdir().unwrap().into_path();
+        Options::parse_from(
+            [
+                "git-cache-http-server",
+                "--cache-dir",
+                cache_dir.to_str().unwrap(),
+            ]
+            .iter()
+            .chain(args),
+        )
+    }
+
     #[tokio::test]
     async fn ref_discovery_new_repo() {
-        let config = Options {
-            cache_dir: tempdir().unwrap().into_path(),
-            port: 0,
-        };
+        let config = options(&[]);
 
         let mut mock_git = Git::default();
 
@@ -257,7 +1085,12 @@ mod tests {
             .expect_authenticate_with_head()
             .with(eq(Uri::from_static("https://example.com/a/b/c")), eq(None))
             .times(1)
-            .returning(|_, _| Ok(Some(String::from("refs/heads/mock"))));
+            .returning(|_, _| Ok(mock_refs()));
+
+        // Local refs differ from upstream.
+        mock_git
+            .expect_local_refs()
+            .returning(|_| Ok(BTreeMap::new()));
 
         mock_git
             .expect_fetch()
@@ -271,11 +1104,15 @@ mod tests {
 
         mock_git
             .expect_advertise_refs()
-            .with(eq(config.cache_dir.join("example.com/a/b/c.git")))
+            .with(
+                eq(config.cache_dir.join("example.com/a/b/c.git")),
+                eq(None),
+                always(),
+            )
             .times(1)
-            .returning(|_| Ok(Box::new("mock git-upload-pack output".as_bytes())));
+            .returning(|_, _, _| Ok(Box::new("mock git-upload-pack output".as_bytes())));
 
-        let app = app(&config, mock_git).await.unwrap();
+        let app = app(&config, mock_git, Shutdown::default()).await.unwrap();
 
         let response = app
             .oneshot(
@@ -320,10 +1157,7 @@ mod tests {
     async fn ref_discovery_existing_repo() {
         // NOTE: Assumes that basic ref discovery of a new repo has passed its tests.
 
-        let config = Options {
-            cache_dir: tempdir().unwrap().into_path(),
-            port: 0,
-        };
+        let config = options(&[]);
 
         let mut mock_git = Git::default();
 
@@ -332,16 +1166,21 @@ mod tests {
         mock_git
             .expect_authenticate_with_head()
             .times(2)
-            .returning(|_, _| Ok(Some(String::from("refs/heads/mock"))));
+            .returning(|_, _| Ok(mock_refs()));
+
+        // Local refs differ from upstream.
+        mock_git
+            .expect_local_refs()
+            .returning(|_| Ok(BTreeMap::new()));
 
         mock_git.expect_fetch().times(2).returning(|_, _, _| Ok(()));
 
         mock_git
             .expect_advertise_refs()
             .times(2)
-            .returning(|_| Ok(Box::new("mock git-upload-pack output".as_bytes())));
+            .returning(|_, _, _| Ok(Box::new("mock git-upload-pack output".as_bytes())));
 
-        let mut app = app(&config, mock_git).await.unwrap();
+        let mut app = app(&config, mock_git, Shutdown::default()).await.unwrap();
 
         // Can't clone Request because axum::body::Body isn't Clone.
 
@@ -368,11 +1207,8 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn upload_pack() {
-        let config = Options {
-            cache_dir: tempdir().unwrap().into_path(),
-            port: 0,
-        };
+    async fn ref_discovery_up_to_date_repo() {
+        let config = options(&[]);
 
         let mut mock_git = Git::default();
 
@@ -380,25 +1216,28 @@ mod tests {
 
         mock_git
             .expect_authenticate_with_head()
-            .with(eq(Uri::from_static("https://example.com/a/b/c")), eq(None))
             .times(1)
-            .returning(|_, _| Ok(None));
+            .returning(|_, _| Ok(mock_refs()));
 
         mock_git
-            .expect_upload_pack()
-            .with(
-                eq(config.cache_dir.join("example.com/a/b/c.git")),
-                eq(Bytes::from("mock client input: 42")),
-            )
+            .expect_local_refs()
+            .with(eq(config.cache_dir.join("example.com/a/b/c.git")))
+            .times(1)
+            .returning(|_| Ok(mock_refs().refs));
+
+        mock_git.expect_fetch().times(0);
+
+        mock_git
+            .expect_advertise_refs()
             .times(1)
-            .returning(|_, _| Ok(Box::new("mock git-upload-pack output".as_bytes())));
+            .returning(|_, _, _| Ok(Box::new("mock git-upload-pack output".as_bytes())));
 
-        let app = app(&config, mock_git).await.unwrap();
+        let app = app(&config, mock_git, Shutdown::default()).await.unwrap();
 
         let response = app
             .oneshot(
-                Request::post("/example.com/a/b/c/git-upload-pack")
-                    .body(Body::from("mock client input: 42"))
+                Request::get("/example.com/a/b/c/info/refs?service=git-upload-pack")
+                    .body(Body::empty())
                     .unwrap(),
             )
             .await
@@ -406,149 +1245,1113 @@ mod tests {
 
         assert_eq!(response.status(), StatusCode::OK);
 
+        // HEAD is still kept up to date.
         assert_eq!(
-            Vec::from_iter(response.headers().get_all(header::CONTENT_TYPE).into_iter()),
-            ["application/x-git-upload-pack-result"]
-        );
-
-        assert_eq!(
-            Vec::from_iter(
-                response
-                    .headers()
-                    .get_all(header::CACHE_CONTROL)
-                    .into_iter()
-            ),
-            ["no-cache"]
-        );
-
-        assert_eq!(
-            response.into_body().collect().await.unwrap().to_bytes(),
-            "mock git-upload-pack output"
+            tokio::fs::read(config.cache_dir.join("example.com/a/b/c.git/HEAD"))
+                .await
+                .unwrap(),
+            b"ref: refs/heads/mock"
         );
     }
 
     #[tokio::test]
-    async fn compressed_upload_pack_request() {
-        // NOTE: Assumes that basic uplaod_pack without compressed requests has passed.
-
-        let config = Options {
-            cache_dir: tempdir().unwrap().into_path(),
-            port: 0,
-        };
+    async fn ref_discovery_fresh_repo() {
+        let config = options(&["--fresh-for", "1h", "--host-fresh-for", "example.org=0s"]);
 
         let mut mock_git = Git::default();
 
-        mock_git.expect_init().times(1).returning(|_| Ok(()));
+        mock_git.expect_init().times(2).returning(|_| Ok(()));
 
         mock_git
             .expect_authenticate_with_head()
-            .times(1)
-            .returning(|_, _| Ok(None));
+            .times(4)
+            .returning(|_, _| Ok(mock_refs()));
 
+        // Fetched only once from example.com, but every time from example.org.
+        // Local refs differ from upstream.
         mock_git
-            .expect_upload_pack()
+            .expect_local_refs()
+            .returning(|_| Ok(BTreeMap::new()));
+
+        mock_git
+            .expect_fetch()
             .with(
+                eq(Uri::from_static("https://example.com/a/b/c")),
                 eq(config.cache_dir.join("example.com/a/b/c.git")),
-                eq(Bytes::from("mock client input: 42")),
+                eq(None),
             )
             .times(1)
-            .returning(|_, _| Ok(Box::new("mock git-upload-pack output".as_bytes())));
+            .returning(|_, _, _| Ok(()));
 
-        let app = app(&config, mock_git).await.unwrap();
+        mock_git
+            .expect_fetch()
+            .with(
+                eq(Uri::from_static("https://example.org/a/b/c")),
+                eq(config.cache_dir.join("example.org/a/b/c.git")),
+                eq(None),
+            )
+            .times(2)
+            .returning(|_, _, _| Ok(()));
 
-        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-        encoder.write_all(b"mock client input: 42").unwrap();
+        mock_git
+            .expect_advertise_refs()
+            .times(4)
+            .returning(|_, _, _| Ok(Box::new("mock git-upload-pack output".as_bytes())));
+
+        let mut app = app(&config, mock_git, Shutdown::default()).await.unwrap();
+
+        for host in ["example.com", "example.com", "example.org", "example.org"] {
+            let response = app
+                .call(
+                    Request::get(format!("/{host}/a/b/c/info/refs?service=git-upload-pack"))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
 
-        let response = app
-            .oneshot(
-                Request::post("/example.com/a/b/c/git-upload-pack")
-                    .header(header::CONTENT_ENCODING, "gzip")
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn protocol_v2() {
+        let config = options(&[]);
+
+        let mut mock_git = Git::default();
+
+        mock_git.expect_init().times(1).returning(|_| Ok(()));
+
+        mock_git
+            .expect_authenticate_with_head()
+            .times(2)
+            .returning(|_, _| Ok(mock_refs()));
+
+        // Local refs differ from upstream.
+        mock_git
+            .expect_local_refs()
+            .returning(|_| Ok(BTreeMap::new()));
+
+        mock_git.expect_fetch().times(1).returning(|_, _, _| Ok(()));
+
+        mock_git
+            .expect_advertise_refs()
+            .with(
+                eq(config.cache_dir.join("example.com/a/b/c.git")),
+                eq(Some(String::from("version=2"))),
+                always(),
+            )
+            .times(1)
+            .returning(|_, _, _| Ok(Box::new("mock capability advertisement".as_bytes())));
+
+        mock_git
+            .expect_upload_pack()
+            .with(
+                eq(config.cache_dir.join("example.com/a/b/c.git")),
+                eq(Some(String::from("version=2"))),
+                always(),
+                always(),
+            )
+            .times(1)
+            // Echo the input, to check that it's passed through.
+            .returning(|_, _, input, _| Ok(input));
+
+        let mut app = app(&config, mock_git, Shutdown::default()).await.unwrap();
+
+        let refs = app
+            .call(
+                Request::get("/example.com/a/b/c/info/refs?service=git-upload-pack")
+                    .header("git-protocol", "version=2")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(refs.status(), StatusCode::OK);
+
+        // No service header in v2.
+        assert_eq!(
+            refs.into_body().collect().await.unwrap().to_bytes(),
+            "mock capability advertisement"
+        );
+
+        let ls_refs = app
+            .oneshot(
+                Request::post("/example.com/a/b/c/git-upload-pack")
+                    .header("git-protocol", "version=2")
+                    .body(Body::from("mock ls-refs command"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(ls_refs.status(), StatusCode::OK);
+
+        assert_eq!(
+            ls_refs.into_body().collect().await.unwrap().to_bytes(),
+            "mock ls-refs command"
+        );
+    }
+
+    #[tokio::test]
+    async fn upload_pack() {
+        let config = options(&[]);
+
+        let mut mock_git = Git::default();
+
+        mock_git.expect_init().times(1).returning(|_| Ok(()));
+
+        mock_git
+            .expect_authenticate_with_head()
+            .with(eq(Uri::from_static("https://example.com/a/b/c")), eq(None))
+            .times(1)
+            .returning(|_, _| Ok(Refs::default()));
+
+        mock_git
+            .expect_upload_pack()
+            .with(
+                eq(config.cache_dir.join("example.com/a/b/c.git")),
+                eq(None),
+                always(),
+                always(),
+            )
+            .times(1)
+            // Echo the input, to check that it's passed through (and decompressed).
+            .returning(|_, _, input, _| Ok(input));
+
+        let app = app(&config, mock_git, Shutdown::default()).await.unwrap();
+
+        let response = app
+            .oneshot(
+                Request::post("/example.com/a/b/c/git-upload-pack")
+                    .body(Body::from("mock client input: 42"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        assert_eq!(
+            Vec::from_iter(response.headers().get_all(header::CONTENT_TYPE).into_iter()),
+            ["application/x-git-upload-pack-result"]
+        );
+
+        assert_eq!(
+            Vec::from_iter(
+                response
+                    .headers()
+                    .get_all(header::CACHE_CONTROL)
+                    .into_iter()
+            ),
+            ["no-cache"]
+        );
+
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            "mock client input: 42"
+        );
+    }
+
+    #[tokio::test]
+    async fn compressed_upload_pack_request() {
+        // NOTE: Assumes that basic uplaod_pack without compressed requests has passed.
+
+        let config = options(&[]);
+
+        let mut mock_git = Git::default();
+
+        mock_git.expect_init().times(1).returning(|_| Ok(()));
+
+        mock_git
+            .expect_authenticate_with_head()
+            .times(1)
+            .returning(|_, _| Ok(Refs::default()));
+
+        mock_git
+            .expect_upload_pack()
+            .with(
+                eq(config.cache_dir.join("example.com/a/b/c.git")),
+                eq(None),
+                always(),
+                always(),
+            )
+            .times(1)
+            // Echo the input, to check that it's passed through (and decompressed).
+            .returning(|_, _, input, _| Ok(input));
+
+        let app = app(&config, mock_git, Shutdown::default()).await.unwrap();
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"mock client input: 42").unwrap();
+
+        let response = app
+            .oneshot(
+                Request::post("/example.com/a/b/c/git-upload-pack")
+                    .header(header::CONTENT_ENCODING, "gzip")
                     .body(Body::from(encoder.finish().unwrap()))
                     .unwrap(),
             )
             .await
             .unwrap();
 
-        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.status(), StatusCode::OK);
+
+        assert_eq!(
+            response.into_body().collect().await.unwrap().to_bytes(),
+            "mock client input: 42"
+        );
+    }
+
+    #[tokio::test]
+    async fn upload_pack_body_size_limit() {
+        let config = options(&["--max-body-size", "16"]);
+
+        let mut mock_git = Git::default();
+
+        mock_git.expect_init().times(1).returning(|_| Ok(()));
+
+        mock_git
+            .expect_authenticate_with_head()
+            .times(4)
+            .returning(|_, _| Ok(Refs::default()));
+
+        // At first, the mock reads all of its input before replying (echoing it). After that,
+        // like git-upload-pack may, it starts replying before reading its input.
+        let mut calls = 0;
+        mock_git
+            .expect_upload_pack()
+            .times(3)
+            .returning(move |_, _, input, _| {
+                calls += 1;
+                match calls {
+                    1 => Ok(input),
+                    _ => Ok(Box::new("mock output: ".as_bytes().chain(input))),
+                }
+            });
+
+        let mut app = app(&config, mock_git, Shutdown::default()).await.unwrap();
+        let mut post = |body: &'static str, content_length: Option<usize>| {
+            let mut request = Request::post("/example.com/a/b/c/git-upload-pack");
+            if let Some(len) = content_length {
+                request = request.header(header::CONTENT_LENGTH, len);
+            }
+            app.call(request.body(Body::from(body)).unwrap())
+        };
+
+        // Rejected without even starting git-upload-pack.
+        let with_content_length = post("mock client input: 42", Some(21)).await.unwrap();
+        assert_eq!(with_content_length.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Noticed before git-upload-pack replies.
+        let before_output = post("mock client input: 42", None).await.unwrap();
+        assert_eq!(before_output.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Noticed after git-upload-pack started replying, so the response is cut short.
+        let after_output = post("mock client input: 42", None).await.unwrap();
+        assert_eq!(after_output.status(), StatusCode::OK);
+        assert!(after_output.into_body().collect().await.is_err());
+
+        let within_limit = post("within limit", None).await.unwrap();
+        assert_eq!(within_limit.status(), StatusCode::OK);
+        assert_eq!(
+            within_limit.into_body().collect().await.unwrap().to_bytes(),
+            "mock output: within limit"
+        );
+    }
+
+    #[test]
+    fn config_file_layering() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(
+            &path,
+            r#"
+            port = 1234
+            fresh-for = "1m"
+            disk-quota = "1G"
+            pin = ["example.com/a"]
+            tls-key = "/etc/git-cache/key.pem"
+            admin-token-file = "/etc/git-cache/admin-token"
+            admin-listen = ["127.0.0.1:8081"]
+            detect-forks = true
+
+            [mirror]
+            "https://example.com/c" = "5m"
+
+            [hosts."example.com"]
+            fresh-for = "5m"
+            "#,
+        )
+        .unwrap();
+
+        let matches = Options::command().get_matches_from([
+            "git-cache-http-server",
+            "--config",
+            path.to_str().unwrap(),
+            "--fresh-for",
+            "2m",
+            "--pin",
+            "example.com/b",
+            "--tls-cert",
+            "/etc/git-cache/cert.pem",
+            "--admin-listen",
+            "unix:/run/git-cache/admin.sock",
+            "--mirror",
+            "example.com/*=1h",
+        ]);
+        let options = Options::from_matches(matches).unwrap();
+
+        assert_eq!(options.port, 1234);
+        assert_eq!(options.fresh_for, Duration::from_secs(120));
+        assert_eq!(options.max_body_size, 64 << 20);
+        assert_eq!(options.disk_quota, Some(1 << 30));
+        assert_eq!(
+            options.host_fresh_for,
+            [("example.com".into(), Duration::from_secs(300))]
+        );
+        assert_eq!(
+            options.pin,
+            [PathBuf::from("example.com/a"), "example.com/b".into()]
+        );
+        assert_eq!(options.tls_key, Some("/etc/git-cache/key.pem".into()));
+        assert_eq!(
+            options.admin_token_file,
+            Some("/etc/git-cache/admin-token".into())
+        );
+        assert_eq!(options.admin_listen.len(), 2);
+        assert_eq!(
+            options.mirror,
+            [
+                Mirror::new("https://example.com/c", Duration::from_secs(300)).unwrap(),
+                Mirror::new("example.com/*", Duration::from_secs(3600)).unwrap(),
+            ]
+        );
+        assert!(options.detect_forks);
+        assert_eq!(
+            options.repo_config().pinned,
+            [
+                PathBuf::from("example.com/a"),
+                "example.com/b".into(),
+                "example.com/c".into()
+            ]
+        );
+
+        // Flags set in the file can be turned off on the command line.
+        let path = dir.path().join("forks.toml");
+        std::fs::write(&path, "detect-forks = true").unwrap();
+        let matches = Options::command().get_matches_from([
+            "git-cache-http-server",
+            "--config",
+            path.to_str().unwrap(),
+            "--no-detect-forks",
+        ]);
+        assert!(!Options::from_matches(matches).unwrap().detect_forks);
+
+        // The TLS certificate and key go together.
+        let matches = Options::command().get_matches_from([
+            "git-cache-http-server",
+            "--tls-cert",
+            "/etc/git-cache/cert.pem",
+        ]);
+        assert!(Options::from_matches(matches).is_err());
+
+        // Without a token, there's no admin API to listen for.
+        let matches = Options::command().get_matches_from([
+            "git-cache-http-server",
+            "--admin-listen",
+            "127.0.0.1:8081",
+        ]);
+        assert!(Options::from_matches(matches).is_err());
+
+        std::fs::write(
+            &path,
+            "port = 1234
+fresh-for = \"1 parsec\"\n",
+        )
+        .unwrap();
+        let matches = Options::command().get_matches_from([
+            "git-cache-http-server",
+            "--config",
+            path.to_str().unwrap(),
+        ]);
+        let err = Options::from_matches(matches).unwrap_err();
+        assert!(err.to_string().contains("line 2"));
+    }
+
+    #[tokio::test]
+    async fn authentication() {
+        let config = options(&[]);
+
+        let mut mock_git = Git::default();
+
+        mock_git.expect_init().times(1).returning(|_| Ok(()));
+
+        mock_git
+            .expect_authenticate_with_head()
+            .times(2)
+            .with(
+                eq(Uri::from_static("https://example.com/a/b/c")),
+                eq(Some(HeaderValue::from_static("mock auth"))),
+            )
+            .returning(|_, _| Ok(mock_refs()));
+
+        // Local refs differ from upstream.
+        mock_git
+            .expect_local_refs()
+            .returning(|_| Ok(BTreeMap::new()));
+
+        mock_git
+            .expect_fetch()
+            .with(
+                eq(Uri::from_static("https://example.com/a/b/c")),
+                eq(config.cache_dir.join("example.com/a/b/c.git")),
+                eq(Some(HeaderValue::from_static("mock auth"))),
+            )
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        mock_git
+            .expect_advertise_refs()
+            .times(1)
+            .returning(|_, _, _| Ok(Box::new([].as_slice())));
+
+        mock_git
+            .expect_upload_pack()
+            .times(1)
+            .returning(|_, _, _, _| Ok(Box::new([].as_slice())));
+
+        let mut app = app(&config, mock_git, Shutdown::default()).await.unwrap();
+
+        let refs = app
+            .call(
+                Request::get("/example.com/a/b/c/info/refs?service=git-upload-pack")
+                    .header(header::AUTHORIZATION, "mock auth")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let upload_pack = app
+            .oneshot(
+                Request::post("/example.com/a/b/c/git-upload-pack")
+                    .header(header::AUTHORIZATION, "mock auth")
+                    .body(Body::from("mock client input: 42"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(refs.status(), StatusCode::OK);
+        assert_eq!(upload_pack.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn upstream_unavailable() {
+        let config = options(&[]);
+
+        let mut mock_git = Git::default();
+
+        mock_git.expect_init().times(1).returning(|_| Ok(()));
+
+        mock_git
+            .expect_authenticate_with_head()
+            .times(2)
+            .returning(|_, _| Ok(mock_refs()));
+
+        // Local refs differ from upstream.
+        mock_git
+            .expect_local_refs()
+            .returning(|_| Ok(BTreeMap::new()));
+
+        let mut fetches = 0;
+        mock_git.expect_fetch().times(2).returning(move |_, _, _| {
+            fetches += 1;
+            match fetches {
+                1 => Ok(()),
+                _ => Err(Error::UpstreamUnavailable(anyhow::anyhow!("mock error"))),
+            }
+        });
+
+        mock_git
+            .expect_advertise_refs()
+            .times(1)
+            .returning(|_, _, _| Ok(Box::new([].as_slice())));
+
+        let mut app = app(&config, mock_git, Shutdown::default()).await.unwrap();
+
+        // Without `--serve-stale-within`, even a previously fetched copy isn't served.
+        for expected in [StatusCode::OK, StatusCode::BAD_GATEWAY] {
+            let response = app
+                .call(
+                    Request::get("/example.com/a/b/c/info/refs?service=git-upload-pack")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn serve_stale() {
+        let config = options(&["--serve-stale-within", "1h"]);
+
+        let mut mock_git = Git::default();
+
+        mock_git.expect_init().times(1).returning(|_| Ok(()));
+
+        let mut authentications = 0;
+        mock_git
+            .expect_authenticate_with_head()
+            .times(5)
+            .returning(move |_, _| {
+                authentications += 1;
+                match authentications {
+                    1 => Ok(mock_refs()),
+                    _ => Err(Error::UpstreamUnavailable(anyhow::anyhow!("mock error"))),
+                }
+            });
+
+        // Local refs differ from upstream.
+        mock_git
+            .expect_local_refs()
+            .returning(|_| Ok(BTreeMap::new()));
+
+        mock_git.expect_fetch().times(1).returning(|_, _, _| Ok(()));
+
+        mock_git
+            .expect_advertise_refs()
+            .times(2)
+            .returning(|_, _, _| Ok(Box::new([].as_slice())));
+
+        mock_git
+            .expect_upload_pack()
+            .times(1)
+            .returning(|_, _, _, _| Ok(Box::new([].as_slice())));
+
+        let mut app = app(&config, mock_git, Shutdown::default()).await.unwrap();
+
+        let fresh = app
+            .call(
+                Request::get("/example.com/a/b/c/info/refs?service=git-upload-pack")
+                    .header(header::AUTHORIZATION, "mock auth")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(fresh.status(), StatusCode::OK);
+        assert_eq!(fresh.headers().get(header::WARNING), None);
+
+        let stale_refs = app
+            .call(
+                Request::get("/example.com/a/b/c/info/refs?service=git-upload-pack")
+                    .header(header::AUTHORIZATION, "mock auth")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let stale_upload_pack = app
+            .call(
+                Request::post("/example.com/a/b/c/git-upload-pack")
+                    .header(header::AUTHORIZATION, "mock auth")
+                    .body(Body::from("mock client input: 42"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        for response in [stale_refs, stale_upload_pack] {
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers().get(header::WARNING).unwrap(),
+                "110 - \"Response is Stale\""
+            );
+            assert_eq!(response.headers().get(header::AGE).unwrap(), "0");
+        }
+
+        // Clients that the upstream hasn't authorized are still turned away.
+        for auth in [Some("other mock auth"), None] {
+            let mut request = Request::get("/example.com/a/b/c/info/refs?service=git-upload-pack");
+            if let Some(auth) = auth {
+                request = request.header(header::AUTHORIZATION, auth);
+            }
+
+            let response = app
+                .call(request.body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        }
+    }
+
+    #[tokio::test]
+    async fn receive_pack() {
+        let config = options(&[]);
+
+        let mut mock_git = Git::default();
+
+        mock_git
+            .expect_receive_pack_advertisement()
+            .with(
+                eq(Uri::from_static("https://example.com/a/b/c")),
+                eq(Some(HeaderValue::from_static("mock auth"))),
+                eq(None),
+            )
+            .times(1)
+            .returning(|_, _, _| {
+                Ok(UpstreamResponse {
+                    status: StatusCode::OK,
+                    headers: HeaderMap::from_iter([(
+                        header::CONTENT_TYPE,
+                        HeaderValue::from_static("application/x-git-receive-pack-advertisement"),
+                    )]),
+                    body: Bytes::from("mock upstream advertisement"),
+                })
+            });
+
+        mock_git
+            .expect_receive_pack()
+            .withf(|upstream, auth, protocol, _| {
+                upstream == "https://example.com/a/b/c"
+                    && auth == &Some(HeaderValue::from_static("mock auth"))
+                    && protocol.is_none()
+            })
+            .times(1)
+            .returning(|_, _, _, _| {
+                Ok(UpstreamResponse {
+                    status: StatusCode::OK,
+                    headers: HeaderMap::from_iter([(
+                        header::CONTENT_TYPE,
+                        HeaderValue::from_static("application/x-git-receive-pack-result"),
+                    )]),
+                    body: Bytes::from("mock upstream result"),
+                })
+            });
+
+        // Pushing doesn't add the repository to the cache.
+        mock_git.expect_init().times(0);
+        mock_git.expect_fetch().times(0);
+
+        let mut app = app(&config, mock_git, Shutdown::default()).await.unwrap();
+
+        let refs = app
+            .call(
+                Request::get("/example.com/a/b/c/info/refs?service=git-receive-pack")
+                    .header(header::AUTHORIZATION, "mock auth")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(refs.status(), StatusCode::OK);
+
+        assert_eq!(
+            Vec::from_iter(refs.headers().get_all(header::CONTENT_TYPE).into_iter()),
+            ["application/x-git-receive-pack-advertisement"]
+        );
+
+        assert_eq!(
+            refs.into_body().collect().await.unwrap().to_bytes(),
+            "mock upstream advertisement"
+        );
+
+        let receive_pack = app
+            .oneshot(
+                Request::post("/example.com/a/b/c/git-receive-pack")
+                    .header(header::AUTHORIZATION, "mock auth")
+                    .body(Body::from("mock client pack"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(receive_pack.status(), StatusCode::OK);
+
+        assert_eq!(
+            receive_pack.into_body().collect().await.unwrap().to_bytes(),
+            "mock upstream result"
+        );
+    }
+
+    #[tokio::test]
+    async fn receive_pack_updates_cached_repo() {
+        let config = options(&[]);
+
+        let mut mock_git = Git::default();
+
+        mock_git.expect_init().times(1).returning(|_| Ok(()));
+
+        mock_git
+            .expect_authenticate_with_head()
+            .times(1)
+            .returning(|_, _| Ok(mock_refs()));
+
+        mock_git
+            .expect_local_refs()
+            .returning(|_| Ok(BTreeMap::new()));
+
+        mock_git
+            .expect_advertise_refs()
+            .times(1)
+            .returning(|_, _, _| Ok(Box::new("mock git-upload-pack output".as_bytes())));
+
+        mock_git
+            .expect_receive_pack()
+            .times(1)
+            .returning(|_, _, _, _| {
+                Ok(UpstreamResponse {
+                    status: StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: Bytes::from("mock upstream result"),
+                })
+            });
+
+        // Once for the ref discovery, and once (with the client's credentials) after the push.
+        let (fetched, mut fetches) = tokio::sync::mpsc::unbounded_channel();
+        mock_git
+            .expect_fetch()
+            .with(
+                eq(Uri::from_static("https://example.com/a/b/c")),
+                eq(config.cache_dir.join("example.com/a/b/c.git")),
+                always(),
+            )
+            .times(2)
+            .returning(move |_, _, auth| {
+                fetched.send(auth).unwrap();
+                Ok(())
+            });
+
+        let mut app = app(&config, mock_git, Shutdown::default()).await.unwrap();
+
+        let refs = app
+            .call(
+                Request::get("/example.com/a/b/c/info/refs?service=git-upload-pack")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(refs.status(), StatusCode::OK);
+        assert_eq!(fetches.recv().await.unwrap(), None);
 
+        let receive_pack = app
+            .oneshot(
+                Request::post("/example.com/a/b/c/git-receive-pack")
+                    .header(header::AUTHORIZATION, "mock auth")
+                    .body(Body::from("mock client pack"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(receive_pack.status(), StatusCode::OK);
         assert_eq!(
-            response.into_body().collect().await.unwrap().to_bytes(),
-            "mock git-upload-pack output"
+            fetches.recv().await.unwrap(),
+            Some(HeaderValue::from_static("mock auth"))
         );
     }
 
     #[tokio::test]
-    async fn authentication() {
-        let config = Options {
-            cache_dir: tempdir().unwrap().into_path(),
-            port: 0,
-        };
+    async fn receive_pack_requires_authentication() {
+        let config = options(&[]);
 
         let mut mock_git = Git::default();
 
-        mock_git.expect_init().times(1).returning(|_| Ok(()));
-
         mock_git
-            .expect_authenticate_with_head()
-            .times(2)
-            .with(
-                eq(Uri::from_static("https://example.com/a/b/c")),
-                eq(Some(HeaderValue::from_static("mock auth"))),
+            .expect_receive_pack_advertisement()
+            .returning(|_, _, _| {
+                Ok(UpstreamResponse {
+                    status: StatusCode::UNAUTHORIZED,
+                    headers: HeaderMap::from_iter([(
+                        header::WWW_AUTHENTICATE,
+                        HeaderValue::from_static("mock authenticate"),
+                    )]),
+                    body: Bytes::new(),
+                })
+            });
+
+        // Nothing to update after a failed push.
+        mock_git.expect_init().times(0);
+        mock_git.expect_fetch().times(0);
+
+        let app = app(&config, mock_git, Shutdown::default()).await.unwrap();
+
+        let refs = app
+            .oneshot(
+                Request::get("/example.com/a/b/c/info/refs?service=git-receive-pack")
+                    .body(Body::empty())
+                    .unwrap(),
             )
-            .returning(|_, _| Ok(Some(String::from("refs/heads/mock"))));
+            .await
+            .unwrap();
+
+        assert_eq!(refs.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            Vec::from_iter(refs.headers().get_all(header::WWW_AUTHENTICATE).into_iter()),
+            ["mock authenticate"]
+        );
+    }
+
+    // `printf "mock lfs object" | sha256sum`
+    const LFS_OID: &str = "cb27cb29a4aad7f769e7a355fadf5a859c9335172e231af46a8a65fcfe1e67ca";
+
+    #[tokio::test]
+    async fn lfs_download() {
+        let config = options(&[]);
+
+        let mut mock_git = Git::default();
 
         mock_git
-            .expect_fetch()
+            .expect_lfs_batch()
             .with(
-                eq(Uri::from_static("https://example.com/a/b/c")),
-                eq(config.cache_dir.join("example.com/a/b/c.git")),
+                eq(Uri::from_static("https://example.com/a/b/c.git")),
                 eq(Some(HeaderValue::from_static("mock auth"))),
+                eq(Bytes::from(r#"{"operation":"download"}"#)),
             )
             .times(1)
-            .returning(|_, _, _| Ok(()));
-
-        mock_git
-            .expect_advertise_refs()
-            .times(1)
-            .returning(|_| Ok(Box::new([].as_slice())));
-
+            .returning(|_, _, _| {
+                Ok(UpstreamResponse {
+                    status: StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: Bytes::from(format!(
+                        r#"{{"objects":[{{"oid":"{LFS_OID}","size":15,"actions":{{"download":{{"href":"https://lfs.example.com/mock"}}}}}}]}}"#
+                    )),
+                })
+            });
+
+        // Only downloaded from the upstream once, and then served from the cache.
         mock_git
-            .expect_upload_pack()
+            .expect_lfs_download()
+            .withf(|action| action.href == "https://lfs.example.com/mock")
             .times(1)
-            .returning(|_, _| Ok(Box::new([].as_slice())));
+            .returning(|_| Ok(Box::new("mock lfs object".as_bytes())));
 
-        let mut app = app(&config, mock_git).await.unwrap();
+        let mut app = app(&config, mock_git, Shutdown::default()).await.unwrap();
 
-        let refs = app
+        let batch = app
             .call(
-                Request::get("/example.com/a/b/c/info/refs?service=git-upload-pack")
+                Request::post("/example.com/a/b/c.git/info/lfs/objects/batch")
+                    .header(header::HOST, "cache.example.com")
                     .header(header::AUTHORIZATION, "mock auth")
+                    .body(Body::from(r#"{"operation":"download"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(batch.status(), StatusCode::OK);
+
+        assert_eq!(
+            Vec::from_iter(batch.headers().get_all(header::CONTENT_TYPE).into_iter()),
+            [lfs::MEDIA_TYPE]
+        );
+
+        let batch: lfs::BatchResponse =
+            serde_json::from_slice(&batch.into_body().collect().await.unwrap().to_bytes()).unwrap();
+        let download = batch.objects[0]
+            .actions
+            .as_ref()
+            .unwrap()
+            .download
+            .clone()
+            .unwrap();
+
+        assert_eq!(
+            download.href,
+            format!("http://cache.example.com/example.com/a/b/c.git/info/lfs/objects/{LFS_OID}")
+        );
+
+        let grant = &download.header[lfs::GRANT_HEADER.as_str()];
+
+        for _ in 0..2 {
+            let object = app
+                .call(
+                    Request::get(format!("/example.com/a/b/c.git/info/lfs/objects/{LFS_OID}"))
+                        .header(lfs::GRANT_HEADER, grant)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(object.status(), StatusCode::OK);
+
+            assert_eq!(
+                object.into_body().collect().await.unwrap().to_bytes(),
+                "mock lfs object"
+            );
+        }
+
+        let no_grant = app
+            .oneshot(
+                Request::get(format!("/example.com/a/b/c.git/info/lfs/objects/{LFS_OID}"))
                     .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
 
-        let upload_pack = app
+        assert_eq!(no_grant.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn lfs_download_verifies_content() {
+        let config = options(&[]);
+
+        let mut mock_git = Git::default();
+
+        mock_git.expect_lfs_batch().times(1).returning(|_, _, _| {
+            Ok(UpstreamResponse {
+                status: StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: Bytes::from(format!(
+                    r#"{{"objects":[{{"oid":"{LFS_OID}","size":15,"actions":{{"download":{{"href":"https://lfs.example.com/mock"}}}}}}]}}"#
+                )),
+            })
+        });
+
+        // Corrupted objects aren't cached, so they're downloaded again.
+        mock_git
+            .expect_lfs_download()
+            .times(2)
+            .returning(|_| Ok(Box::new("mock lfs objecT".as_bytes())));
+
+        let mut app = app(&config, mock_git, Shutdown::default()).await.unwrap();
+
+        let batch = app
+            .call(
+                Request::post("/example.com/a/b/c.git/info/lfs/objects/batch")
+                    .header(header::HOST, "cache.example.com")
+                    .body(Body::from(r#"{"operation":"download"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let batch: lfs::BatchResponse =
+            serde_json::from_slice(&batch.into_body().collect().await.unwrap().to_bytes()).unwrap();
+        let download = batch.objects[0]
+            .actions
+            .as_ref()
+            .unwrap()
+            .download
+            .clone()
+            .unwrap();
+        let grant = &download.header[lfs::GRANT_HEADER.as_str()];
+
+        for _ in 0..2 {
+            let object = app
+                .call(
+                    Request::get(format!("/example.com/a/b/c.git/info/lfs/objects/{LFS_OID}"))
+                        .header(lfs::GRANT_HEADER, grant)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            // Already streaming by the time the content is checked, so the body is aborted.
+            assert_eq!(object.status(), StatusCode::OK);
+            assert!(object.into_body().collect().await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn lfs_upload() {
+        let config = options(&[]);
+
+        let mut mock_git = Git::default();
+
+        // Uploads are relayed as is.
+        mock_git.expect_lfs_batch().times(1).returning(|_, _, _| {
+            Ok(UpstreamResponse {
+                status: StatusCode::OK,
+                headers: HeaderMap::from_iter([(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static(lfs::MEDIA_TYPE),
+                )]),
+                body: Bytes::from("mock upstream batch response"),
+            })
+        });
+
+        let app = app(&config, mock_git, Shutdown::default()).await.unwrap();
+
+        let batch = app
             .oneshot(
-                Request::post("/example.com/a/b/c/git-upload-pack")
-                    .header(header::AUTHORIZATION, "mock auth")
-                    .body(Body::from("mock client input: 42"))
+                Request::post("/example.com/a/b/c.git/info/lfs/objects/batch")
+                    .header(header::HOST, "cache.example.com")
+                    .body(Body::from(r#"{"operation":"upload"}"#))
                     .unwrap(),
             )
             .await
             .unwrap();
 
-        assert_eq!(refs.status(), StatusCode::OK);
-        assert_eq!(upload_pack.status(), StatusCode::OK);
+        assert_eq!(batch.status(), StatusCode::OK);
+
+        assert_eq!(
+            batch.into_body().collect().await.unwrap().to_bytes(),
+            "mock upstream batch response"
+        );
+    }
+
+    #[tokio::test]
+    async fn lfs_requires_authentication() {
+        let config = options(&[]);
+
+        let mut mock_git = Git::default();
+
+        mock_git.expect_lfs_batch().returning(|_, _, _| {
+            Ok(UpstreamResponse {
+                status: StatusCode::UNAUTHORIZED,
+                headers: HeaderMap::from_iter([(
+                    header::WWW_AUTHENTICATE,
+                    HeaderValue::from_static("mock authenticate"),
+                )]),
+                body: Bytes::new(),
+            })
+        });
+
+        mock_git.expect_lfs_download().times(0);
+
+        let app = app(&config, mock_git, Shutdown::default()).await.unwrap();
+
+        let batch = app
+            .oneshot(
+                Request::post("/example.com/a/b/c.git/info/lfs/objects/batch")
+                    .header(header::HOST, "cache.example.com")
+                    .body(Body::from(r#"{"operation":"download"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(batch.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            Vec::from_iter(
+                batch
+                    .headers()
+                    .get_all(header::WWW_AUTHENTICATE)
+                    .into_iter()
+            ),
+            ["mock authenticate"]
+        );
     }
 
     #[tokio::test]
     async fn non_existent_repository() {
-        let config = Options {
-            cache_dir: tempdir().unwrap().into_path(),
-            port: 0,
-        };
+        let config = options(&[]);
 
         let mut mock_git = Git::default();
 
@@ -559,7 +2362,7 @@ mod tests {
             .expect_authenticate_with_head()
             .returning(|_, _| Err(Error::NotFound));
 
-        let mut app = app(&config, mock_git).await.unwrap();
+        let mut app = app(&config, mock_git, Shutdown::default()).await.unwrap();
 
         let refs = app
             .call(
@@ -585,10 +2388,7 @@ mod tests {
 
     #[tokio::test]
     async fn requires_authentication() {
-        let config = Options {
-            cache_dir: tempdir().unwrap().into_path(),
-            port: 0,
-        };
+        let config = options(&[]);
 
         let mut mock_git = Git::default();
 
@@ -601,7 +2401,7 @@ mod tests {
             )))
         });
 
-        let mut app = app(&config, mock_git).await.unwrap();
+        let mut app = app(&config, mock_git, Shutdown::default()).await.unwrap();
 
         let refs = app
             .call(
@@ -638,4 +2438,223 @@ mod tests {
             ["mock authenticate"]
         );
     }
+
+    #[tokio::test]
+    async fn metrics() {
+        let config = options(&[]);
+
+        let mut mock_git = Git::default();
+        mock_git.expect_init().times(1).returning(|_| Ok(()));
+        mock_git
+            .expect_authenticate_with_head()
+            .times(1)
+            .returning(|_, _| Ok(mock_refs()));
+        mock_git
+            .expect_local_refs()
+            .times(1)
+            .returning(|_| Ok(mock_refs().refs));
+        mock_git
+            .expect_advertise_refs()
+            .times(1)
+            .returning(|_, _, _| Ok(Box::new("mock git-upload-pack output".as_bytes())));
+
+        let mut app = app(&config, mock_git, Shutdown::default()).await.unwrap();
+
+        let refs = app
+            .call(
+                Request::get("/example.com/a/b/c/info/refs?service=git-upload-pack")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(refs.status(), StatusCode::OK);
+
+        let metrics = app
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(metrics.status(), StatusCode::OK);
+        assert_eq!(
+            metrics.headers().get(header::CONTENT_TYPE).unwrap(),
+            metrics::MEDIA_TYPE
+        );
+
+        // Other tests update the same metrics concurrently, so only check what's specific to this
+        // one.
+        let metrics = metrics.into_body().collect().await.unwrap().to_bytes();
+        let metrics = std::str::from_utf8(&metrics).unwrap();
+        assert!(metrics.contains(
+            "git_cache_http_requests_total{route=\"upload-pack-advertisement\",status=\"200\"}"
+        ));
+        assert!(metrics.contains("git_cache_upstream_refs_duration_seconds_count"));
+        assert!(metrics.contains("git_cache_repositories 1\n"));
+        assert!(metrics.contains("git_cache_disk_usage_bytes "));
+        assert!(metrics.contains("git_cache_git_processes "));
+    }
+
+    #[tokio::test]
+    async fn health_and_readiness() {
+        let config = options(&[]);
+
+        let mut mock_git = Git::default();
+        mock_git.expect_check().times(3).returning(|| Ok(()));
+
+        let shutdown = Shutdown::default();
+        let mut app = app(&config, mock_git, shutdown.clone()).await.unwrap();
+
+        let mut get = |path| app.call(Request::get(path).body(Body::empty()).unwrap());
+
+        let healthz = get("/healthz").await.unwrap();
+        assert_eq!(healthz.status(), StatusCode::OK);
+
+        let readyz = get("/readyz").await.unwrap();
+        assert_eq!(readyz.status(), StatusCode::OK);
+
+        // Losing the lock file (e.g. to an overeager cleanup) makes us unready.
+        std::fs::remove_file(config.cache_dir.join(".git-cache")).unwrap();
+        let readyz = get("/readyz").await.unwrap();
+        assert_eq!(readyz.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        shutdown.start();
+        let readyz = get("/readyz").await.unwrap();
+        assert_eq!(readyz.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = readyz.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "shutting down, cache directory not locked");
+
+        // Liveness doesn't depend on any of that.
+        let healthz = get("/healthz").await.unwrap();
+        assert_eq!(healthz.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn admin_api() {
+        let token_file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(token_file.path(), "secret\n").unwrap();
+        let config = options(&["--admin-token-file", token_file.path().to_str().unwrap()]);
+
+        let mut mock_git = Git::default();
+        mock_git.expect_init().times(1).returning(|_| Ok(()));
+        mock_git
+            .expect_authenticate_with_head()
+            .times(1)
+            .returning(|_, _| Ok(mock_refs()));
+        mock_git
+            .expect_local_refs()
+            .times(1)
+            .returning(|_| Ok(mock_refs().refs));
+        mock_git
+            .expect_advertise_refs()
+            .times(1)
+            .returning(|_, _, _| Ok(Box::new("mock git-upload-pack output".as_bytes())));
+        mock_git.expect_fetch().times(1).returning(|_, _, _| Ok(()));
+
+        let mut app = app(&config, mock_git, Shutdown::default()).await.unwrap();
+
+        let mut send = |method, path, token: Option<&str>| {
+            let mut request = Request::builder().method(method).uri(path);
+            if let Some(token) = token {
+                request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+            }
+            app.call(request.body(Body::empty()).unwrap())
+        };
+        let json = |response: Response| async {
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        let response = send(Method::GET, "/_admin/repos", None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = send(Method::GET, "/_admin/repos", Some("wrong"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = send(
+            Method::GET,
+            "/example.com/a/info/refs?service=git-upload-pack",
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        drop(response);
+
+        let response = send(Method::GET, "/_admin/repos", Some("secret"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let list = json(response).await;
+        assert_eq!(list[0]["name"], "example.com/a.git");
+        assert_eq!(list[0]["upstream"], "https://example.com/a");
+        assert!(list[0]["last_fetch"].is_string());
+        assert_eq!(list[0]["pinned"], false);
+
+        let response = send(Method::PUT, "/_admin/pins/example.com/a", Some("secret"))
+            .await
+            .unwrap();
+        assert_eq!(json(response).await["pinned"], true);
+
+        let response = send(
+            Method::POST,
+            "/_admin/refresh/example.com/a",
+            Some("secret"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = send(Method::GET, "/_admin/repos/example.com/a", Some("secret"))
+            .await
+            .unwrap();
+        let details = json(response).await;
+        assert_eq!(details["head"], "refs/heads/mock");
+        assert_eq!(details["fetching"], false);
+
+        let response = send(
+            Method::DELETE,
+            "/_admin/repos/example.com/a",
+            Some("secret"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = send(Method::GET, "/_admin/repos/example.com/a", Some("secret"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn admin_api_on_separate_listener() {
+        let token_file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(token_file.path(), "secret").unwrap();
+        let config = options(&[
+            "--admin-token-file",
+            token_file.path().to_str().unwrap(),
+            "--admin-listen",
+            "127.0.0.1:0",
+        ]);
+
+        let app = app(&config, Git::default(), Shutdown::default())
+            .await
+            .unwrap();
+
+        let request = || {
+            Request::get("/_admin/repos")
+                .header(header::AUTHORIZATION, "Bearer secret")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = app
+            .layer(Extension(admin::Listener))
+            .oneshot(request())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
 }
@@ -0,0 +1,96 @@
+//! Graceful shutdown: stop accepting connections, and let the ones in flight finish. Background
+//! tasks are stopped right away.
+
+use std::future::Future;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+/// A handle to start shutting down, and to wait for connections to drain.
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown {
+    started: CancellationToken,
+    connections: TaskTracker,
+    background: TaskTracker,
+}
+
+impl Shutdown {
+    /// Stop accepting new connections, and ask the open ones to close once idle.
+    pub fn start(&self) {
+        self.started.cancel();
+        self.connections.close();
+        self.background.close();
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.started.is_cancelled()
+    }
+
+    /// Wait until shutdown starts.
+    pub async fn started(&self) {
+        self.started.cancelled().await
+    }
+
+    /// Spawn the task serving a connection, to be waited for by `drained`.
+    pub fn track<F>(&self, connection: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.connections.spawn(connection);
+    }
+
+    /// Wait until shutdown has started and all connections have closed.
+    pub async fn drained(&self) {
+        self.connections.wait().await
+    }
+
+    /// Spawn a background task, to be stopped (at its next await point) once shutdown starts.
+    pub fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let started = self.started.clone();
+        self.background.spawn(async move {
+            tokio::select! {
+                _ = started.cancelled() => {}
+                _ = task => {}
+            }
+        });
+    }
+
+    /// Wait until shutdown has started and all background tasks have stopped.
+    pub async fn stopped(&self) {
+        self.background.wait().await
+    }
+}
+
+/// Wait for SIGTERM or SIGINT.
+pub async fn signal_received() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("failed to listen for SIGINT");
+    tokio::select! {
+        _ = terminate.recv() => tracing::info!("Received SIGTERM"),
+        _ = interrupt.recv() => tracing::info!("Received SIGINT"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn background_tasks() {
+        let shutdown = Shutdown::default();
+        shutdown.spawn(std::future::pending());
+
+        let stopped = tokio::time::timeout(Duration::from_millis(50), shutdown.stopped());
+        assert!(stopped.await.is_err());
+
+        shutdown.start();
+        let stopped = tokio::time::timeout(Duration::from_secs(10), shutdown.stopped());
+        assert!(stopped.await.is_ok());
+    }
+}
@@ -0,0 +1,172 @@
+//! Integration with systemd: socket activation, readiness notification and the watchdog.
+//!
+//! These follow the protocols documented in `sd_listen_fds(3)`, `sd_notify(3)` and
+//! `sd_watchdog_enabled(3)`, without linking to libsystemd. Outside of systemd, all of this is a
+//! no-op.
+
+use std::ffi::OsStr;
+use std::io;
+use std::os::fd::{FromRawFd, RawFd};
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use tokio::net::{TcpListener, UnixListener};
+
+use crate::listen::Listener;
+
+/// The first file descriptor passed by systemd.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The environment variables for socket activation.
+///
+/// Removing them from our own environment isn't thread-safe, so they're left in place, and
+/// removed from the environment of children instead.
+pub const LISTEN_ENV: [&str; 3] = ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"];
+
+/// Take the listening sockets passed by systemd, if any.
+pub fn listeners() -> io::Result<Vec<Listener>> {
+    let count = listen_fds(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+    );
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // SAFETY: systemd passes these file descriptors to us, and nothing else owns them.
+            unsafe { listener(fd) }
+        })
+        .collect()
+}
+
+/// The number of file descriptors passed to this process.
+fn listen_fds(pid: Option<&str>, fds: Option<&str>) -> RawFd {
+    if pid.and_then(|pid| pid.parse().ok()) != Some(std::process::id()) {
+        return 0;
+    }
+    fds.and_then(|fds| fds.parse().ok()).unwrap_or(0)
+}
+
+/// # Safety
+///
+/// `fd` must be an open socket owned by nobody else.
+unsafe fn listener(fd: RawFd) -> io::Result<Listener> {
+    if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut address: libc::sockaddr_storage = std::mem::zeroed();
+    let mut len = std::mem::size_of_val(&address) as libc::socklen_t;
+    if libc::getsockname(fd, std::ptr::addr_of_mut!(address).cast(), &mut len) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    match libc::c_int::from(address.ss_family) {
+        libc::AF_INET | libc::AF_INET6 => {
+            let listener = std::net::TcpListener::from_raw_fd(fd);
+            listener.set_nonblocking(true)?;
+            Ok(Listener::Tcp(TcpListener::from_std(listener)?))
+        }
+        libc::AF_UNIX => {
+            let listener = std::os::unix::net::UnixListener::from_raw_fd(fd);
+            listener.set_nonblocking(true)?;
+            let path = listener
+                .local_addr()?
+                .as_pathname()
+                .map(PathBuf::from)
+                .unwrap_or_default();
+            Ok(Listener::Unix(UnixListener::from_std(listener)?, path))
+        }
+        family => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("inherited file descriptor {fd} has unsupported address family {family}"),
+        )),
+    }
+}
+
+/// Notify systemd of a state change, e.g. `READY=1`.
+pub fn notify(state: &str) {
+    let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(err) = send(&socket, state) {
+        tracing::warn!(error = ?err, "failed to notify systemd of {state}");
+    }
+}
+
+fn send(socket: &OsStr, state: &str) -> io::Result<()> {
+    // Sockets in the abstract namespace are specific to Linux.
+    let address = match socket.as_bytes() {
+        #[cfg(target_os = "linux")]
+        [b'@', name @ ..] => SocketAddr::from_abstract_name(name)?,
+        _ => SocketAddr::from_pathname(socket)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
+
+/// Send `WATCHDOG=1` at half the interval expected by systemd, forever, if the watchdog is enabled.
+pub async fn watchdog() {
+    let Some(interval) = watchdog_interval(
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+    ) else {
+        return;
+    };
+
+    let mut interval = tokio::time::interval(interval / 2);
+    loop {
+        interval.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
+
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>) -> Option<Duration> {
+    if pid.is_some_and(|pid| pid.parse().ok() != Some(std::process::id())) {
+        return None;
+    }
+    let usec = usec?.parse().ok().filter(|usec| *usec > 0)?;
+    Some(Duration::from_micros(usec))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn environment_parsing() {
+        let pid = std::process::id().to_string();
+
+        assert_eq!(listen_fds(Some(&pid), Some("2")), 2);
+        assert_eq!(listen_fds(Some("1"), Some("2")), 0);
+        assert_eq!(listen_fds(None, Some("2")), 0);
+        assert_eq!(listen_fds(Some(&pid), None), 0);
+
+        assert_eq!(
+            watchdog_interval(Some("30000000"), Some(&pid)),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            watchdog_interval(Some("30000000"), None),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(watchdog_interval(Some("30000000"), Some("1")), None);
+        assert_eq!(watchdog_interval(Some("0"), None), None);
+        assert_eq!(watchdog_interval(None, None), None);
+    }
+
+    #[test]
+    fn notifications() {
+        let path = tempfile::tempdir().unwrap().into_path().join("notify");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        send(path.as_os_str(), "READY=1").unwrap();
+
+        let mut buf = [0; 64];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+    }
+}
@@ -0,0 +1,165 @@
+//! TLS termination, with certificates that are reloaded when their files change.
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+/// How often to check whether the certificate or key files have changed.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A certificate chain and private key, read from PEM files.
+pub struct Certificate {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl Certificate {
+    /// Load the certificate chain at `cert_path` and the private key at `key_path`.
+    pub fn load(cert_path: &Path, key_path: &Path) -> io::Result<Arc<Self>> {
+        let provider = Arc::new(ring::default_provider());
+        let current = read(cert_path, key_path, &provider)?;
+        Ok(Arc::new(Self {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            provider,
+            current: RwLock::new(current),
+        }))
+    }
+
+    /// An acceptor for TLS connections using this (and any reloaded) certificate.
+    pub fn acceptor(self: &Arc<Self>) -> io::Result<TlsAcceptor> {
+        let mut config = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        // Let the client pick HTTP/2 if it supports it, like it could with plain HTTP.
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    /// Reload the certificate whenever its files change, forever.
+    ///
+    /// Connections keep using the previous certificate until the new one has been read
+    /// successfully. As the certificate and key are often replaced one after the other, a pair
+    /// that doesn't match is only logged, and tried again on the next change.
+    pub async fn reload_on_change(self: Arc<Self>) {
+        let mut modified = self.modified();
+        loop {
+            tokio::time::sleep(RELOAD_CHECK_INTERVAL).await;
+            let now = self.modified();
+            if now == modified {
+                continue;
+            }
+            modified = now;
+            if let Err(err) = self.reload() {
+                tracing::error!(error = ?err, "failed to reload TLS certificate, keeping the old one");
+            }
+        }
+    }
+
+    fn reload(&self) -> io::Result<()> {
+        let reloaded = read(&self.cert_path, &self.key_path, &self.provider)?;
+        *self.current.write().unwrap() = reloaded;
+        tracing::info!("Reloaded TLS certificate from {:?}", self.cert_path);
+        Ok(())
+    }
+
+    fn modified(&self) -> [Option<SystemTime>; 2] {
+        [&self.cert_path, &self.key_path].map(|path| {
+            std::fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+    }
+}
+
+impl ResolvesServerCert for Certificate {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+impl fmt::Debug for Certificate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Certificate")
+            .field("cert_path", &self.cert_path)
+            .field("key_path", &self.key_path)
+            .finish_non_exhaustive()
+    }
+}
+
+fn read(
+    cert_path: &Path,
+    key_path: &Path,
+    provider: &CryptoProvider,
+) -> io::Result<Arc<CertifiedKey>> {
+    let invalid = |path: &Path, message: &dyn fmt::Display| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{path:?}: {message}"))
+    };
+
+    let mut cert_file = io::BufReader::new(std::fs::File::open(cert_path)?);
+    let certs = rustls_pemfile::certs(&mut cert_file).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(invalid(cert_path, &"no certificates found"));
+    }
+
+    let mut key_file = io::BufReader::new(std::fs::File::open(key_path)?);
+    let key = rustls_pemfile::private_key(&mut key_file)?
+        .ok_or_else(|| invalid(key_path, &"no private key found"))?;
+    let key = provider
+        .key_provider
+        .load_private_key(key)
+        .map_err(|err| invalid(key_path, &err))?;
+
+    let certified = CertifiedKey::new(certs, key);
+    certified
+        .keys_match()
+        .map_err(|err| invalid(key_path, &err))?;
+    Ok(Arc::new(certified))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CERT: &[u8] = include_bytes!("../tests/fixtures/tls-cert.pem");
+    const KEY: &[u8] = include_bytes!("../tests/fixtures/tls-key.pem");
+    const OTHER_KEY: &[u8] = include_bytes!("../tests/fixtures/tls-other-key.pem");
+
+    #[test]
+    fn loading_and_reloading() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        std::fs::write(&cert_path, CERT).unwrap();
+        std::fs::write(&key_path, OTHER_KEY).unwrap();
+
+        // The key must match the certificate.
+        let err = Certificate::load(&cert_path, &key_path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        std::fs::write(&key_path, KEY).unwrap();
+        let certificate = Certificate::load(&cert_path, &key_path).unwrap();
+        let before = certificate.current.read().unwrap().clone();
+
+        // A bad pair keeps the current certificate.
+        std::fs::write(&key_path, OTHER_KEY).unwrap();
+        assert!(certificate.reload().is_err());
+        assert!(Arc::ptr_eq(&before, &certificate.current.read().unwrap()));
+
+        std::fs::write(&key_path, KEY).unwrap();
+        certificate.reload().unwrap();
+        assert!(!Arc::ptr_eq(&before, &certificate.current.read().unwrap()));
+    }
+}